@@ -0,0 +1,62 @@
+use crate::config::{Command, Settings};
+use crate::ssh::keypair::{self, KeyStore};
+
+/// Runs a host key subcommand and returns the process exit code.
+pub fn run(command: &Command, settings: &Settings) -> i32 {
+    let result = match command {
+        Command::Keygen => settings.key_store().and_then(|store| keygen(&store, settings)),
+        Command::Rotate => settings.key_store().and_then(|store| rotate(&store, settings)),
+        Command::Fingerprint { hostname } => fingerprint(settings, hostname.as_deref()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn keygen(store: &KeyStore, settings: &Settings) -> Result<(), String> {
+    for &algorithm in &settings.host_key_algorithms {
+        let path = store.dir.join(algorithm.private_file());
+        if store.exists(algorithm) {
+            println!("{} already exists, skipping", path.display());
+            continue;
+        }
+        store.generate(algorithm)?;
+        println!("Generated {}", path.display());
+    }
+    Ok(())
+}
+
+fn rotate(store: &KeyStore, settings: &Settings) -> Result<(), String> {
+    for &algorithm in &settings.host_key_algorithms {
+        let key = store.rotate(algorithm)?;
+        println!("Rotated {}: {}", algorithm.private_file(), keypair::sha256_fingerprint(key.public_key()));
+    }
+    println!("Previous keys moved to {}", store.dir.join(keypair::RETIRED_DIR).display());
+    Ok(())
+}
+
+fn fingerprint(settings: &Settings, hostname: Option<&str>) -> Result<(), String> {
+    // Public keys only, so no passphrase is needed here.
+    let store = KeyStore { dir: &settings.keypair_dir, strict: false, passphrase: None };
+    let port = settings.listeners()?.first().map_or(22, |l| l.address.port());
+
+    for &algorithm in &settings.host_key_algorithms {
+        let key = store.load_public(algorithm)?;
+        println!("{} {}", keypair::sha256_fingerprint(&key), algorithm.public_file());
+        println!("{} {}", keypair::md5_fingerprint(&key), algorithm.public_file());
+        println!("{}", keypair::randomart(&key, algorithm));
+
+        if let Some(host) = hostname {
+            println!("{}", keypair::known_hosts_line(host, port, &key));
+            for record in keypair::sshfp_records(host, algorithm, &key) {
+                println!("{}", record);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
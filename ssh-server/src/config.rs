@@ -0,0 +1,568 @@
+use std::{fs, net::{SocketAddr, ToSocketAddrs}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use log::LevelFilter;
+use tokio::sync::watch;
+use russh::{MethodKind, MethodSet, Preferred, SshId, keys::{Certificate, PrivateKey}, server::Config};
+use crate::ssh::auth::KeyPolicy;
+use crate::ssh::authorized_keys::AuthorizedKeys;
+use crate::ssh::user_ca::{self, UserCa};
+use crate::ssh::keypair::{HostKeyAlgorithm, KeyStore, PassphraseSource};
+use crate::ssh::limiter::RateLimitSettings;
+use crate::ssh::policy::AuthPolicyKind;
+
+/// Receiving end of the live settings. Each new connection takes a snapshot.
+pub type SettingsRx = watch::Receiver<Arc<Settings>>;
+
+/// Command-line flags. Every flag can also be set through its `SSH_SERVER_*`
+/// env var and overrides the value read from the config file.
+#[derive(Debug, Clone, Parser)]
+#[command(name = "ssh-server", version, about = "culturecode SSH server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML config file
+    #[arg(short, long, env = "SSH_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, env = "SSH_SERVER_HOST")]
+    pub host: Option<String>,
+
+    #[arg(short, long, env = "SSH_SERVER_PORT")]
+    pub port: Option<u16>,
+
+    /// Address to listen on, repeatable. Replaces `host`/`port` and any
+    /// `[[listeners]]` from the config file
+    #[arg(short, long, env = "SSH_SERVER_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Directory holding the host keypair
+    #[arg(long, env = "SSH_SERVER_KEYPAIR_DIR")]
+    pub keypair_dir: Option<PathBuf>,
+
+    /// Refuse to start on a missing or mismatched host public key file
+    /// instead of rewriting it (default true)
+    #[arg(long, env = "SSH_SERVER_STRICT_HOST_KEYS")]
+    pub strict_host_keys: Option<bool>,
+
+    /// Env var holding the host key passphrase
+    #[arg(long, env = "SSH_SERVER_HOST_KEY_PASSPHRASE_ENV", group = "passphrase")]
+    pub host_key_passphrase_env: Option<String>,
+
+    /// File holding the host key passphrase
+    #[arg(long, env = "SSH_SERVER_HOST_KEY_PASSPHRASE_FILE", group = "passphrase")]
+    pub host_key_passphrase_file: Option<PathBuf>,
+
+    /// systemd credential holding the host key passphrase
+    #[arg(long, env = "SSH_SERVER_HOST_KEY_PASSPHRASE_CREDENTIAL", group = "passphrase")]
+    pub host_key_passphrase_credential: Option<String>,
+
+    /// Warn when a host certificate has fewer than this many seconds left
+    #[arg(long, env = "SSH_SERVER_HOST_CERTIFICATE_EXPIRY_WARNING", value_name = "SECS")]
+    pub host_certificate_expiry_warning: Option<u64>,
+
+    /// Host key type to load or generate, repeatable
+    #[arg(long = "host-key-algorithm", env = "SSH_SERVER_HOST_KEY_ALGORITHMS", value_delimiter = ',')]
+    pub host_key_algorithms: Vec<HostKeyAlgorithm>,
+
+    #[arg(long, env = "SSH_SERVER_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Who may log in with a key or certificate
+    #[arg(long, env = "SSH_SERVER_AUTH_POLICY")]
+    pub auth_policy: Option<AuthPolicyKind>,
+
+    /// Route that clients without a usable key reach through keyboard-interactive
+    #[arg(long, env = "SSH_SERVER_GUEST_ROUTE")]
+    pub guest_route: Option<String>,
+
+    /// authorized_keys file for the allowlist, repeatable
+    #[arg(long = "authorized-keys", env = "SSH_SERVER_AUTHORIZED_KEYS", value_delimiter = ',')]
+    pub authorized_keys_files: Vec<PathBuf>,
+
+    /// File of CA public keys trusted to sign user certificates
+    #[arg(long, env = "SSH_SERVER_TRUSTED_USER_CA_KEYS")]
+    pub trusted_user_ca_keys: Option<PathBuf>,
+
+    /// File of CA public keys trusted to sign the host certificates
+    #[arg(long, env = "SSH_SERVER_HOST_CA_KEYS")]
+    pub host_ca_keys: Option<PathBuf>,
+
+    /// Revoked keys and certificates: a binary KRL or `ssh-keygen -k` text
+    #[arg(long, env = "SSH_SERVER_REVOKED_KEYS")]
+    pub revoked_keys: Option<PathBuf>,
+
+    /// File holding the TOTP secrets of keys enrolled for `totp_routes`
+    #[arg(long, env = "SSH_SERVER_TOTP_SECRETS_PATH")]
+    pub totp_secrets_path: Option<PathBuf>,
+
+    /// JSONL file recording the client keys seen
+    #[arg(long, env = "SSH_SERVER_AUTH_LOG_PATH")]
+    pub auth_log_path: Option<PathBuf>,
+
+    /// Seconds to wait for sessions to close on shutdown
+    #[arg(long, env = "SSH_SERVER_SHUTDOWN_GRACE_PERIOD", value_name = "SECS")]
+    pub shutdown_grace_period: Option<u64>,
+
+    /// User to switch to once the listeners are bound
+    #[arg(long, env = "SSH_SERVER_USER")]
+    pub user: Option<String>,
+
+    #[arg(long, env = "SSH_SERVER_GROUP")]
+    pub group: Option<String>,
+
+    #[arg(long, env = "SSH_SERVER_SERVER_ID")]
+    pub server_id: Option<String>,
+
+    /// Seconds of inactivity before a connection is dropped, 0 disables
+    #[arg(long, env = "SSH_SERVER_INACTIVITY_TIMEOUT", value_name = "SECS")]
+    pub inactivity_timeout: Option<u64>,
+
+    #[arg(long, env = "SSH_SERVER_AUTH_REJECTION_TIME", value_name = "SECS")]
+    pub auth_rejection_time: Option<u64>,
+
+    #[arg(long, env = "SSH_SERVER_AUTH_REJECTION_TIME_INITIAL", value_name = "SECS")]
+    pub auth_rejection_time_initial: Option<u64>,
+
+    #[arg(long, env = "SSH_SERVER_MAX_AUTH_ATTEMPTS")]
+    pub max_auth_attempts: Option<usize>,
+
+    /// Seconds between keepalives, 0 disables
+    #[arg(long, env = "SSH_SERVER_KEEPALIVE_INTERVAL", value_name = "SECS")]
+    pub keepalive_interval: Option<u64>,
+
+    #[arg(long, env = "SSH_SERVER_KEEPALIVE_MAX")]
+    pub keepalive_max: Option<usize>,
+
+    #[arg(long, env = "SSH_SERVER_WINDOW_SIZE")]
+    pub window_size: Option<u32>,
+
+    #[arg(long, env = "SSH_SERVER_MAXIMUM_PACKET_SIZE")]
+    pub maximum_packet_size: Option<u32>,
+
+    #[arg(long, env = "SSH_SERVER_CHANNEL_BUFFER_SIZE")]
+    pub channel_buffer_size: Option<usize>,
+
+    #[arg(long, env = "SSH_SERVER_EVENT_BUFFER_SIZE")]
+    pub event_buffer_size: Option<usize>,
+
+    #[arg(long, env = "SSH_SERVER_NODELAY")]
+    pub nodelay: Option<bool>,
+}
+
+/// Host key maintenance. Without a subcommand the server runs.
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Generate any configured host keys that do not exist yet
+    Keygen,
+    /// Replace the configured host keys, moving the old ones to `retired/`
+    Rotate,
+    /// Print fingerprints and randomart of the host keys. With a hostname,
+    /// also print `known_hosts` lines and SSHFP DNS records
+    Fingerprint {
+        hostname: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub host: String,
+    pub port: u16,
+    /// Listeners to bind; when empty a single one is bound on `host:port`
+    pub listeners: Vec<ListenerSettings>,
+    pub keypair_dir: PathBuf,
+    /// Host keys offered to clients, each stored in its own file
+    pub host_key_algorithms: Vec<HostKeyAlgorithm>,
+    /// Refuse to start on a host public key file that is missing or does not
+    /// match its private key, instead of rewriting it. Corrupt or exposed
+    /// private keys are refused either way
+    pub strict_host_keys: bool,
+    /// Encrypt host keys at rest with a passphrase read from this source
+    pub host_key_passphrase: Option<PassphraseSource>,
+    /// Seconds before a host certificate expires to start warning about it
+    pub host_certificate_expiry_warning: u64,
+    /// CA public keys a host certificate must be signed by
+    pub host_ca_keys: Option<PathBuf>,
+    pub log_level: LevelFilter,
+    /// Shown to clients before authentication
+    pub banner: Option<String>,
+    /// Usernames accepted as routes; empty accepts any username
+    pub routes: Vec<String>,
+    /// Who may log in: `routes` follows the route settings below, `open`
+    /// takes any key, `allowlist` only listed keys, `certificate` only user
+    /// certificates
+    pub auth_policy: AuthPolicyKind,
+    /// Routes that only accept keys listed in `authorized_keys_files`
+    pub private_routes: Vec<String>,
+    /// Routes that ask for a TOTP code after the key is accepted
+    pub totp_routes: Vec<String>,
+    /// Per-key TOTP secrets, written by the server as keys enrol
+    pub totp_secrets_path: Option<PathBuf>,
+    /// Route for clients without a usable key, reached through
+    /// keyboard-interactive; unset disables guest logins
+    pub guest_route: Option<String>,
+    /// OpenSSH `authorized_keys` files backing the allowlist
+    pub authorized_keys_files: Vec<PathBuf>,
+    /// CA public keys whose user certificates are accepted on every route,
+    /// private ones included
+    pub trusted_user_ca_keys: Option<PathBuf>,
+    /// Revoked keys, certificate serials and key ids
+    pub revoked_keys: Option<PathBuf>,
+    /// Append-only JSONL file recording client keys across restarts; unset
+    /// keeps the log in memory
+    pub auth_log_path: Option<PathBuf>,
+    /// Most client keys the auth log keeps
+    pub auth_log_capacity: usize,
+    /// Seconds after which a key not seen again is forgotten, 0 disables
+    pub auth_log_ttl: u64,
+    /// Parsed from `authorized_keys_files` by `load`
+    #[serde(skip)]
+    pub authorized_keys: Arc<AuthorizedKeys>,
+    /// Parsed from `trusted_user_ca_keys` and `revoked_keys` by `load`
+    #[serde(skip)]
+    pub user_ca: Arc<UserCa>,
+    /// Seconds to wait for sessions to close on SIGTERM/SIGINT
+    pub shutdown_grace_period: u64,
+    /// User to switch to after binding; the group defaults to its primary group
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Client keys accepted for public key authentication
+    pub key_policy: KeyPolicy,
+    /// Per-IP connection limits and bans
+    pub rate_limit: RateLimitSettings,
+    pub ssh: SshSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    pub address: SocketAddr,
+    /// Routes reachable through this listener; empty allows every route
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Set `IPV6_V6ONLY` on IPv6 sockets; unset keeps the OS default
+    #[serde(default)]
+    pub v6_only: Option<bool>,
+}
+
+impl ListenerSettings {
+    pub fn new(address: SocketAddr) -> Self {
+        Self { address, routes: Vec::new(), v6_only: None }
+    }
+
+    pub fn route_allowed(&self, route: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|r| r == route)
+    }
+}
+
+/// Knobs passed through to `russh::server::Config`. Durations are in seconds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshSettings {
+    pub server_id: Option<String>,
+    pub inactivity_timeout: u64,
+    pub auth_rejection_time: u64,
+    pub auth_rejection_time_initial: u64,
+    pub max_auth_attempts: usize,
+    pub keepalive_interval: u64,
+    pub keepalive_max: usize,
+    pub window_size: u32,
+    pub maximum_packet_size: u32,
+    pub channel_buffer_size: usize,
+    pub event_buffer_size: usize,
+    pub nodelay: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 2222,
+            listeners: Vec::new(),
+            keypair_dir: PathBuf::from("./keypair"),
+            host_key_algorithms: vec![HostKeyAlgorithm::Ed25519],
+            strict_host_keys: true,
+            host_key_passphrase: None,
+            host_certificate_expiry_warning: 30 * 24 * 3600,
+            log_level: LevelFilter::Info,
+            banner: None,
+            routes: Vec::new(),
+            auth_policy: AuthPolicyKind::default(),
+            private_routes: Vec::new(),
+            guest_route: None,
+            totp_routes: Vec::new(),
+            totp_secrets_path: None,
+            authorized_keys_files: Vec::new(),
+            authorized_keys: Arc::default(),
+            trusted_user_ca_keys: None,
+            host_ca_keys: None,
+            revoked_keys: None,
+            user_ca: Arc::default(),
+            auth_log_path: None,
+            auth_log_capacity: 1000,
+            auth_log_ttl: 0,
+            shutdown_grace_period: 5,
+            user: None,
+            group: None,
+            key_policy: KeyPolicy::default(),
+            rate_limit: RateLimitSettings::default(),
+            ssh: SshSettings::default(),
+        }
+    }
+}
+
+impl Default for SshSettings {
+    fn default() -> Self {
+        let russh = Config::default();
+        Self {
+            server_id: None,
+            inactivity_timeout: 3600,
+            auth_rejection_time: 3,
+            auth_rejection_time_initial: 0,
+            max_auth_attempts: russh.max_auth_attempts,
+            keepalive_interval: 0,
+            keepalive_max: russh.keepalive_max,
+            window_size: russh.window_size,
+            maximum_packet_size: russh.maximum_packet_size,
+            channel_buffer_size: russh.channel_buffer_size,
+            event_buffer_size: russh.event_buffer_size,
+            nodelay: russh.nodelay,
+        }
+    }
+}
+
+impl Settings {
+    /// Defaults, overlaid with the config file (if any), overlaid with CLI/env.
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut settings = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        settings.apply_cli(cli);
+        settings.validate()?;
+        settings.authorized_keys = Arc::new(AuthorizedKeys::load(&settings.authorized_keys_files)?);
+        settings.user_ca = Arc::new(UserCa::load(settings.trusted_user_ca_keys.as_deref(), settings.revoked_keys.as_deref())?);
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let listener_routes = self.listeners.iter().flat_map(|l| &l.routes);
+        if let Some(route) = self.routes.iter().chain(&self.private_routes).chain(&self.totp_routes).chain(&self.guest_route).chain(listener_routes)
+            .find(|r| r.is_empty() || r.contains(char::is_whitespace))
+        {
+            return Err(format!("Invalid route name: {:?}", route));
+        }
+        if !self.private_routes.is_empty() && self.authorized_keys_files.is_empty() && self.trusted_user_ca_keys.is_none() {
+            return Err("private_routes need authorized_keys_files or trusted_user_ca_keys".to_string());
+        }
+        if let Some(route) = self.guest_route.as_deref().filter(|r| self.is_private_route(r)) {
+            return Err(format!("guest_route {:?} cannot be a private route", route));
+        }
+        if self.auth_policy != AuthPolicyKind::Routes && !(self.private_routes.is_empty() && self.totp_routes.is_empty()) {
+            return Err("private_routes and totp_routes need auth_policy = \"routes\"".to_string());
+        }
+        if self.auth_policy == AuthPolicyKind::Allowlist && self.authorized_keys_files.is_empty() {
+            return Err("auth_policy = \"allowlist\" needs authorized_keys_files".to_string());
+        }
+        if self.auth_policy == AuthPolicyKind::Certificate && self.trusted_user_ca_keys.is_none() {
+            return Err("auth_policy = \"certificate\" needs trusted_user_ca_keys".to_string());
+        }
+        if let Some(route) = self.totp_routes.iter().find(|r| !self.is_private_route(r)) {
+            return Err(format!("totp_routes need to be private_routes too, {:?} is not", route));
+        }
+        if !self.totp_routes.is_empty() && self.totp_secrets_path.is_none() {
+            return Err("totp_routes need totp_secrets_path".to_string());
+        }
+        if let Some(route) = self.guest_route.as_deref().filter(|r| self.is_totp_route(r)) {
+            return Err(format!("guest_route {:?} cannot require TOTP", route));
+        }
+        if self.rate_limit.ipv6_prefix > 128 {
+            return Err("rate_limit.ipv6_prefix must be at most 128".to_string());
+        }
+        if self.auth_log_capacity == 0 {
+            return Err("auth_log_capacity must be at least 1".to_string());
+        }
+        if self.host_key_algorithms.is_empty() {
+            return Err("host_key_algorithms must not be empty".to_string());
+        }
+        let algorithms = &self.host_key_algorithms;
+        if algorithms.iter().enumerate().any(|(i, a)| algorithms[..i].contains(a)) {
+            return Err("host_key_algorithms contains duplicates".to_string());
+        }
+        Ok(())
+    }
+
+    /// The listeners to bind, falling back to `host:port`.
+    pub fn listeners(&self) -> Result<Vec<ListenerSettings>, String> {
+        if !self.listeners.is_empty() {
+            return Ok(self.listeners.clone());
+        }
+        let addr = (self.host.as_str(), self.port).to_socket_addrs()
+            .map_err(|e| format!("Invalid listen address {}:{}: {}", self.host, self.port, e))?
+            .next()
+            .ok_or_else(|| format!("No address found for {}:{}", self.host, self.port))?;
+        Ok(vec![ListenerSettings::new(addr)])
+    }
+
+    /// Reads the host key passphrase, if one is configured.
+    pub fn key_store(&self) -> Result<KeyStore<'_>, String> {
+        let passphrase = self.host_key_passphrase.as_ref().map(PassphraseSource::read).transpose()?;
+        Ok(KeyStore { dir: &self.keypair_dir, strict: self.strict_host_keys, passphrase })
+    }
+
+    /// Checks the host certificates in the keypair directory; they hold no
+    /// secrets, so this works without the passphrase.
+    pub fn check_host_certificates(&self) -> Result<Vec<Certificate>, String> {
+        let store = KeyStore { dir: &self.keypair_dir, strict: self.strict_host_keys, passphrase: None };
+        let cas = self.host_ca_keys.as_deref().map(user_ca::load_ca_keys).transpose()?.unwrap_or_default();
+        store.check_certificates(
+            &self.host_key_algorithms,
+            &cas,
+            Duration::from_secs(self.host_certificate_expiry_warning),
+        )
+    }
+
+    pub fn auth_log_ttl(&self) -> Duration {
+        Duration::from_secs(self.auth_log_ttl)
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_period)
+    }
+
+    pub fn route_allowed(&self, route: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|r| r == route)
+    }
+
+    pub fn is_private_route(&self, route: &str) -> bool {
+        self.private_routes.iter().any(|r| r == route)
+    }
+
+    pub fn is_totp_route(&self, route: &str) -> bool {
+        self.totp_routes.iter().any(|r| r == route)
+    }
+
+    /// Public keys always; keyboard-interactive only for guests. `none` is
+    /// never offered: OpenSSH probes with it before trying its keys, so
+    /// accepting it would turn every client into a guest.
+    fn auth_methods(&self) -> MethodSet {
+        let mut methods = MethodSet::empty();
+        methods.push(MethodKind::PublicKey);
+        if self.guest_route.is_some() {
+            methods.push(MethodKind::KeyboardInteractive);
+        }
+        methods
+    }
+
+    /// Names of the settings that differ from `other` but only apply on restart.
+    pub fn restart_only_changes(&self, other: &Settings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.host != other.host || self.port != other.port || self.listeners != other.listeners {
+            changed.push("listeners");
+        }
+        if self.keypair_dir != other.keypair_dir
+            || self.host_key_algorithms != other.host_key_algorithms
+            || self.host_key_passphrase != other.host_key_passphrase
+        {
+            changed.push("host keys");
+        }
+        if self.auth_log_path != other.auth_log_path
+            || self.auth_log_capacity != other.auth_log_capacity
+            || self.auth_log_ttl != other.auth_log_ttl
+        {
+            changed.push("auth log");
+        }
+        if self.rate_limit.ban_list_path != other.rate_limit.ban_list_path { changed.push("ban_list_path"); }
+        if self.totp_secrets_path != other.totp_secrets_path { changed.push("totp_secrets_path"); }
+        if self.guest_route.is_some() != other.guest_route.is_some() { changed.push("guest_route"); }
+        if self.user != other.user || self.group != other.group { changed.push("user/group"); }
+        if self.ssh != other.ssh { changed.push("ssh"); }
+        changed
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        let ssh = &mut self.ssh;
+        if let Some(v) = &cli.host { self.host = v.clone(); }
+        if let Some(v) = cli.port { self.port = v; }
+        if !cli.listen.is_empty() {
+            self.listeners = cli.listen.iter().copied().map(ListenerSettings::new).collect();
+        }
+        if let Some(v) = &cli.keypair_dir { self.keypair_dir = v.clone(); }
+        if let Some(v) = cli.strict_host_keys { self.strict_host_keys = v; }
+        if let Some(v) = &cli.host_key_passphrase_env {
+            self.host_key_passphrase = Some(PassphraseSource::Env(v.clone()));
+        }
+        if let Some(v) = &cli.host_key_passphrase_file {
+            self.host_key_passphrase = Some(PassphraseSource::File(v.clone()));
+        }
+        if let Some(v) = &cli.host_key_passphrase_credential {
+            self.host_key_passphrase = Some(PassphraseSource::Credential(v.clone()));
+        }
+        if let Some(v) = cli.host_certificate_expiry_warning { self.host_certificate_expiry_warning = v; }
+        if !cli.host_key_algorithms.is_empty() { self.host_key_algorithms = cli.host_key_algorithms.clone(); }
+        if let Some(v) = cli.log_level { self.log_level = v; }
+        if let Some(v) = cli.auth_policy { self.auth_policy = v; }
+        if let Some(v) = &cli.guest_route { self.guest_route = Some(v.clone()); }
+        if !cli.authorized_keys_files.is_empty() { self.authorized_keys_files = cli.authorized_keys_files.clone(); }
+        if let Some(v) = &cli.trusted_user_ca_keys { self.trusted_user_ca_keys = Some(v.clone()); }
+        if let Some(v) = &cli.host_ca_keys { self.host_ca_keys = Some(v.clone()); }
+        if let Some(v) = &cli.revoked_keys { self.revoked_keys = Some(v.clone()); }
+        if let Some(v) = &cli.totp_secrets_path { self.totp_secrets_path = Some(v.clone()); }
+        if let Some(v) = &cli.auth_log_path { self.auth_log_path = Some(v.clone()); }
+        if let Some(v) = cli.shutdown_grace_period { self.shutdown_grace_period = v; }
+        if let Some(v) = &cli.user { self.user = Some(v.clone()); }
+        if let Some(v) = &cli.group { self.group = Some(v.clone()); }
+        if let Some(v) = &cli.server_id { ssh.server_id = Some(v.clone()); }
+        if let Some(v) = cli.inactivity_timeout { ssh.inactivity_timeout = v; }
+        if let Some(v) = cli.auth_rejection_time { ssh.auth_rejection_time = v; }
+        if let Some(v) = cli.auth_rejection_time_initial { ssh.auth_rejection_time_initial = v; }
+        if let Some(v) = cli.max_auth_attempts { ssh.max_auth_attempts = v; }
+        if let Some(v) = cli.keepalive_interval { ssh.keepalive_interval = v; }
+        if let Some(v) = cli.keepalive_max { ssh.keepalive_max = v; }
+        if let Some(v) = cli.window_size { ssh.window_size = v; }
+        if let Some(v) = cli.maximum_packet_size { ssh.maximum_packet_size = v; }
+        if let Some(v) = cli.channel_buffer_size { ssh.channel_buffer_size = v; }
+        if let Some(v) = cli.event_buffer_size { ssh.event_buffer_size = v; }
+        if let Some(v) = cli.nodelay { ssh.nodelay = v; }
+    }
+
+    pub fn russh_config(&self, keys: Vec<PrivateKey>, certificates: Vec<Certificate>) -> Config {
+        let ssh = &self.ssh;
+        let defaults = Config::default();
+        Config {
+            server_id: ssh.server_id.clone().map(|id| SshId::Standard(id.into())).unwrap_or(defaults.server_id),
+            inactivity_timeout: seconds(ssh.inactivity_timeout),
+            auth_rejection_time: Duration::from_secs(ssh.auth_rejection_time),
+            auth_rejection_time_initial: Some(Duration::from_secs(ssh.auth_rejection_time_initial)),
+            max_auth_attempts: ssh.max_auth_attempts,
+            keepalive_interval: seconds(ssh.keepalive_interval),
+            keepalive_max: ssh.keepalive_max,
+            window_size: ssh.window_size,
+            maximum_packet_size: ssh.maximum_packet_size,
+            channel_buffer_size: ssh.channel_buffer_size,
+            event_buffer_size: ssh.event_buffer_size,
+            nodelay: ssh.nodelay,
+            keys,
+            certificates,
+            methods: self.auth_methods(),
+            preferred: Preferred {
+                ..Preferred::default()
+            },
+            ..defaults
+        }
+    }
+}
+
+/// Zero means "disabled" for the optional russh timeouts.
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
@@ -1,19 +1,41 @@
+mod commands;
+mod config;
+mod privileges;
+mod signals;
 mod ssh;
 
-use std::{sync::Arc, path::Path};
-use russh::{Preferred,server::Config};
-use ssh::{server::SshServer, keypair};
+use std::sync::Arc;
+use clap::Parser;
+use tokio::sync::watch;
+use config::{Cli, Settings};
+use ssh::{auth::AuthLog, limiter::Limiter, policy::ConfiguredPolicy, server::SshServer, listener::Listener, totp::TotpStore};
 
 #[tokio::main]
 async fn main() {
-    let host: &str = "0.0.0.0";
-    let port: u16 = 2222;
+    let cli = Cli::parse();
+    let settings = match Settings::load(&cli) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
+    // Filter through the global max level so SIGHUP can change it later.
     env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(log::LevelFilter::Trace)
         .init();
+    log::set_max_level(settings.log_level);
+
+    if !settings.authorized_keys_files.is_empty() {
+        log::info!("Loaded {} authorized key(s)", settings.authorized_keys.len());
+    }
 
-    let private_key = match keypair::load_keypair(Path::new("./keypair")) {
+    if let Some(command) = &cli.command {
+        std::process::exit(commands::run(command, &settings));
+    }
+
+    let host_keys = match settings.key_store().and_then(|store| store.load_all(&settings.host_key_algorithms)) {
         Ok(k) => k,
         Err(e) => {
             log::error!("Keypair error: {}", e);
@@ -21,20 +43,84 @@ async fn main() {
         }
     };
 
-    let config = Config {
-        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-        auth_rejection_time: std::time::Duration::from_secs(3),
-        auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![private_key],
-        preferred: Preferred {
-            ..Preferred::default()
-        },
-        ..Default::default()
+    let host_certificates = match settings.check_host_certificates() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Host certificate error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let auth_log = match AuthLog::open(
+        settings.auth_log_path.as_deref(),
+        settings.auth_log_capacity,
+        settings.auth_log_ttl(),
+    ) {
+        Ok(a) => a,
+        Err(e) => {
+            log::error!("Auth log error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let limiter = match Limiter::open(settings.rate_limit.ban_list_path.as_deref()) {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Ban list error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let totp = match TotpStore::open(settings.totp_secrets_path.as_deref()) {
+        Ok(t) => t,
+        Err(e) => {
+            log::error!("TOTP secrets error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let config = Arc::new(settings.russh_config(host_keys, host_certificates));
+    let listeners = match settings.listeners().and_then(Listener::open_all) {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Listener error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Host keys and sockets are in hand; nothing below needs root.
+    if let Err(e) = privileges::drop_privileges(&settings) {
+        log::error!("Failed to drop privileges: {}", e);
+        std::process::exit(1);
+    }
+
+    let (settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+    let mut shutdown_signals = match signals::ShutdownSignals::install()
+        .and_then(|s| signals::reload_on_sighup(cli, settings_tx).map(|_| s))
+    {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to install signal handlers: {}", e);
+            std::process::exit(1);
+        }
     };
+    let server = SshServer::new(settings_rx.clone(), auth_log, limiter, totp)
+        .with_policy(Arc::new(ConfiguredPolicy::new(settings_rx.clone())));
 
-    let config = Arc::new(config);
-    let server = SshServer::new();
+    for listener in &listeners {
+        log::info!("SSH server running at {}", listener.settings.address);
+    }
+    let shutdown = async {
+        let name = shutdown_signals.recv().await;
+        log::info!("{} received, no longer accepting connections", name);
+    };
+    if let Err(e) = server.run(config, listeners, shutdown).await {
+        log::error!("Server error: {}", e);
+    }
 
-    log::info!("SSH server running at {}:{}", host, port);
-    server.run(config, (host, port)).await.unwrap();
+    let grace = settings_rx.borrow().shutdown_grace_period();
+    tokio::select! {
+        _ = server.shutdown(grace) => log::info!("Shutdown complete"),
+        name = shutdown_signals.recv() => log::warn!("{} received again, exiting now", name),
+    }
 }
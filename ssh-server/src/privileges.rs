@@ -0,0 +1,47 @@
+use nix::unistd::{self, Gid, Group, Uid, User};
+use crate::config::Settings;
+
+/// Switches to the configured user/group once the listeners are bound and
+/// the host keys are loaded. Does nothing when no user is configured.
+pub fn drop_privileges(settings: &Settings) -> Result<(), String> {
+    let Some(user_name) = &settings.user else {
+        if unistd::geteuid().is_root() {
+            log::warn!("Running as root; set `user` to drop privileges after binding");
+        }
+        return Ok(());
+    };
+
+    let user = User::from_name(user_name)
+        .map_err(|e| format!("Failed to look up user {}: {}", user_name, e))?
+        .ok_or_else(|| format!("Unknown user: {}", user_name))?;
+    let gid = match &settings.group {
+        Some(group_name) => Group::from_name(group_name)
+            .map_err(|e| format!("Failed to look up group {}: {}", group_name, e))?
+            .ok_or_else(|| format!("Unknown group: {}", group_name))?
+            .gid,
+        None => user.gid,
+    };
+
+    if unistd::geteuid() == user.uid && unistd::getegid() == gid {
+        return Ok(());
+    }
+
+    // Supplementary groups and the gid have to go while we are still root.
+    unistd::setgroups(&[gid]).map_err(|e| format!("setgroups failed: {}", e))?;
+    unistd::setgid(gid).map_err(|e| format!("setgid({}) failed: {}", gid, e))?;
+    unistd::setuid(user.uid).map_err(|e| format!("setuid({}) failed: {}", user.uid, e))?;
+
+    if !user.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err("Privileges could be regained after setuid".to_string());
+    }
+
+    log::info!("Dropped privileges to {} (uid {}, gid {})", user.name, user.uid, gid_name(gid));
+    Ok(())
+}
+
+fn gid_name(gid: Gid) -> String {
+    match Group::from_gid(gid) {
+        Ok(Some(group)) => format!("{} {}", gid, group.name),
+        _ => gid.to_string(),
+    }
+}
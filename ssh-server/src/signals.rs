@@ -0,0 +1,58 @@
+use std::sync::Arc;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use crate::config::{Cli, Settings};
+
+/// Re-reads the config on SIGHUP and publishes it to new connections.
+/// Live sessions keep the snapshot they started with. An invalid config is
+/// rejected and the previous one stays in effect.
+pub fn reload_on_sighup(cli: Cli, tx: watch::Sender<Arc<Settings>>) -> Result<(), String> {
+    let mut hangup = signal(SignalKind::hangup()).map_err(|e| e.to_string())?;
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading config");
+            match Settings::load(&cli) {
+                Ok(settings) => {
+                    let restart_only = settings.restart_only_changes(&tx.borrow());
+                    if !restart_only.is_empty() {
+                        log::warn!("Config changes need a restart to apply: {}", restart_only.join(", "));
+                    }
+                    log::set_max_level(settings.log_level);
+                    if let Err(e) = settings.check_host_certificates() {
+                        log::error!("{}", e);
+                    }
+                    tx.send_replace(Arc::new(settings));
+                    log::info!("Config reloaded");
+                }
+                Err(e) => log::error!("Config reload failed, keeping previous config: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// SIGTERM and SIGINT, installed up front so neither kills the process
+/// before the server has had a chance to drain its sessions.
+pub struct ShutdownSignals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl ShutdownSignals {
+    pub fn install() -> Result<Self, String> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate()).map_err(|e| e.to_string())?,
+            interrupt: signal(SignalKind::interrupt()).map_err(|e| e.to_string())?,
+        })
+    }
+
+    /// Waits for the next shutdown signal and returns its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
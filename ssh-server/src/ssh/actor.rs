@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use russh::{ChannelId, server::Handle};
+
+use crate::ssh::app::App;
+use crate::ssh::commands;
+use crate::ssh::session::{ChannelDetails, SharedContext};
+
+/// Live session channels, keyed by connection id and channel. Only holds
+/// mailboxes: each `App` is owned by its actor task, so a slow render never
+/// blocks other sessions.
+pub type Clients = Arc<Mutex<HashMap<(usize, ChannelId), SessionHandle>>>;
+
+/// Clears the TUI and leaves the cursor at the top of the client's terminal.
+const CLEAR_SCREEN: &[u8] = b"\x1b[2J\x1b[H\r\n";
+const FAREWELL: &[u8] = b"The server is restarting, see you soon!\r\n";
+/// How often a TUI redraws by itself, e.g. to keep its clock current
+const TICK: Duration = Duration::from_secs(1);
+/// Messages queued per session. When full, the connection waits for the
+/// actor and stops reading, so a fast client runs out of SSH window instead
+/// of filling memory.
+const MAILBOX_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum SessionMsg {
+    /// Bytes typed by the client
+    Input(Vec<u8>),
+    /// New terminal size, columns then rows
+    Resize(u16, u16),
+    /// The client asked for a shell, with the channel's pty and env details
+    Shell(ChannelDetails),
+    /// The client asked to run a command; `pty` if it requested one first
+    Exec { command: String, pty: bool, details: ChannelDetails },
+    Tick,
+    /// The client has no more input: finish up and close the channel
+    Eof,
+    /// The client closed the channel
+    Close,
+    /// The server is stopping: say goodbye and close the channel
+    Shutdown,
+}
+
+/// Mailbox of one session's actor.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    sender: Sender<SessionMsg>,
+    pub context: SharedContext,
+}
+
+impl SessionHandle {
+    /// Waits for room in the mailbox. False once the actor has stopped.
+    pub async fn send(&self, msg: SessionMsg) -> bool {
+        self.sender.send(msg).await.is_ok()
+    }
+}
+
+/// What an actor writes to its channel, in order.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelWrite {
+    Data(Vec<u8>),
+    Stderr(Vec<u8>),
+    ExitStatus(u32),
+    Eof,
+    Close,
+}
+
+/// Writes an actor's output to its channel until the actor stops or the
+/// connection goes away. The actor itself never waits on the connection,
+/// which may be waiting for room in the actor's mailbox.
+async fn write_channel(handle: Handle, channel: ChannelId, mut writes: UnboundedReceiver<ChannelWrite>) {
+    while let Some(write) = writes.recv().await {
+        let written = match write {
+            ChannelWrite::Data(data) => handle.data(channel, data).await.is_ok(),
+            ChannelWrite::Stderr(data) => handle.extended_data(channel, 1, data).await.is_ok(),
+            ChannelWrite::ExitStatus(status) => handle.exit_status_request(channel, status).await.is_ok(),
+            ChannelWrite::Eof => handle.eof(channel).await.is_ok(),
+            ChannelWrite::Close => handle.close(channel).await.is_ok(),
+        };
+        if !written {
+            break;
+        }
+    }
+}
+
+/// Stops the actors of a connection that has ended and drops their
+/// registry entries, with them their apps and terminals.
+pub async fn close_connection(clients: &Clients, id: usize) {
+    let (closed, left) = {
+        let mut clients = clients.lock().await;
+        let keys: Vec<_> = clients.keys().filter(|(client, _)| *client == id).copied().collect();
+        let closed: Vec<_> = keys.iter().filter_map(|key| clients.remove(key)).collect();
+        (closed, clients.len())
+    };
+    // Sent without the lock: an actor with a full mailbox needs it to stop.
+    for session in closed {
+        session.send(SessionMsg::Close).await;
+    }
+    log::debug!("Connection #{id} closed, {left} session channel(s) left");
+}
+
+/// Owns one channel's `App` and handles its messages in order. Nothing is
+/// drawn before the shell starts. Stops on exit, exec, close or shutdown, or
+/// when every mailbox handle is gone.
+pub struct SessionActor {
+    key: (usize, ChannelId),
+    app: App,
+    /// Set once the shell starts
+    running: bool,
+    /// Frames flushed by a TUI app's terminal
+    output: Option<UnboundedReceiver<Vec<u8>>>,
+    /// Queue of the channel's writer task
+    writes: UnboundedSender<ChannelWrite>,
+    clients: Clients,
+}
+
+impl SessionActor {
+    /// Registers the session in `clients` and starts its task, with a
+    /// second one writing to the channel through `handle`.
+    pub async fn spawn(
+        key: (usize, ChannelId),
+        app: App,
+        output: Option<UnboundedReceiver<Vec<u8>>>,
+        handle: Handle,
+        clients: Clients,
+    ) -> SessionHandle {
+        let (writes, pending) = unbounded_channel();
+        tokio::spawn(write_channel(handle, key.1, pending));
+        Self::start(key, app, output, writes, clients).await.0
+    }
+
+    /// `spawn` with the channel's writes going to `writes`; also returns the
+    /// actor's task.
+    async fn start(
+        key: (usize, ChannelId),
+        app: App,
+        output: Option<UnboundedReceiver<Vec<u8>>>,
+        writes: UnboundedSender<ChannelWrite>,
+        clients: Clients,
+    ) -> (SessionHandle, JoinHandle<()>) {
+        let (sender, receiver) = channel(MAILBOX_SIZE);
+        let session = SessionHandle { sender, context: app.context().clone() };
+        clients.lock().await.insert(key, session.clone());
+        let actor = Self { key, app, running: false, output, writes, clients };
+        (session, tokio::spawn(actor.run(receiver)))
+    }
+
+    async fn run(mut self, mut receiver: Receiver<SessionMsg>) {
+        let mut ticker = interval(TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            self.flush();
+            let msg = tokio::select! {
+                msg = receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = ticker.tick() => SessionMsg::Tick,
+            };
+            if !self.handle(msg).await {
+                break;
+            }
+        }
+        self.clients.lock().await.remove(&self.key);
+    }
+
+    /// Queues the frames the app has drawn so far.
+    fn flush(&mut self) {
+        let Some(output) = &mut self.output else { return };
+        while let Ok(frame) = output.try_recv() {
+            let _ = self.writes.send(ChannelWrite::Data(frame));
+        }
+    }
+
+    fn write(&self, write: ChannelWrite) {
+        let _ = self.writes.send(write);
+    }
+
+    /// False when the session is over.
+    async fn handle(&mut self, msg: SessionMsg) -> bool {
+        let channel = self.key.1;
+        match msg {
+            SessionMsg::Input(data) => {
+                let mut should_exit = self.app.handle_input(&data);
+                should_exit |= self.app.confirm_totp().await;
+                self.app.serve();
+                if should_exit {
+                    self.flush();
+                    self.write(ChannelWrite::Data(CLEAR_SCREEN.to_vec()));
+                    self.write(ChannelWrite::ExitStatus(0));
+                    self.write(ChannelWrite::Close);
+                    log::info!("Client close connection | {}, channel: {channel}", self.app.context().read());
+                    log::debug!("{:?}", self.app.context().read());
+                    return false;
+                }
+            }
+            SessionMsg::Resize(width, height) => {
+                self.app.resize(width, height);
+                if self.running {
+                    self.app.serve();
+                }
+            }
+            SessionMsg::Shell(details) => {
+                self.app.set_channel(details);
+                self.running = true;
+                self.app.serve();
+                self.flush();
+                self.write(ChannelWrite::Data(self.app.content.clone().into_bytes()));
+            }
+            SessionMsg::Exec { command, pty, details } => {
+                let output = commands::run(&command, &self.app.context().read(), &details);
+                let newlines = |text: String| if pty { text.replace('\n', "\r\n") } else { text };
+                if !output.stdout.is_empty() {
+                    self.write(ChannelWrite::Data(newlines(output.stdout).into_bytes()));
+                }
+                if !output.stderr.is_empty() {
+                    self.write(ChannelWrite::Stderr(newlines(output.stderr).into_bytes()));
+                }
+                self.write(ChannelWrite::ExitStatus(output.exit_status));
+                self.write(ChannelWrite::Eof);
+                self.write(ChannelWrite::Close);
+                return false;
+            }
+            SessionMsg::Tick => {
+                if self.running && self.app.is_tui() {
+                    self.app.serve();
+                }
+            }
+            SessionMsg::Eof => {
+                self.flush();
+                self.write(ChannelWrite::ExitStatus(0));
+                self.write(ChannelWrite::Close);
+                return false;
+            }
+            SessionMsg::Close => return false,
+            SessionMsg::Shutdown => {
+                self.flush();
+                self.write(ChannelWrite::Data([CLEAR_SCREEN, FAREWELL].concat()));
+                self.write(ChannelWrite::Eof);
+                self.write(ChannelWrite::Close);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh::keys::ssh_encoding::Decode;
+    use tokio::time::timeout;
+    use crate::ssh::session::SessionContext;
+
+    struct Started {
+        session: SessionHandle,
+        task: JoinHandle<()>,
+        writes: UnboundedReceiver<ChannelWrite>,
+    }
+
+    fn channel_id(id: u32) -> ChannelId {
+        ChannelId::decode(&mut &id.to_be_bytes()[..]).unwrap()
+    }
+
+    async fn start(clients: &Clients, id: usize, channel: u32) -> Started {
+        let context = SharedContext::new(SessionContext::new(id, None));
+        let (writes, pending) = unbounded_channel();
+        let key = (id, channel_id(channel));
+        let (session, task) = SessionActor::start(key, App::start(context), None, writes, clients.clone()).await;
+        Started { session, task, writes: pending }
+    }
+
+    async fn finished(task: JoinHandle<()>) {
+        timeout(Duration::from_secs(1), task).await.expect("actor still running").expect("actor panicked");
+    }
+
+    fn written(writes: &mut UnboundedReceiver<ChannelWrite>) -> Vec<ChannelWrite> {
+        std::iter::from_fn(|| writes.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn close_connection_stops_only_its_actors() {
+        let clients = Clients::default();
+        let first = start(&clients, 1, 0).await;
+        let second = start(&clients, 1, 1).await;
+        let other = start(&clients, 2, 0).await;
+
+        close_connection(&clients, 1).await;
+        finished(first.task).await;
+        finished(second.task).await;
+        assert_eq!(clients.lock().await.keys().copied().collect::<Vec<_>>(), vec![(2, channel_id(0))]);
+
+        close_connection(&clients, 2).await;
+        finished(other.task).await;
+        assert!(clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn channel_close_stops_the_actor() {
+        let clients = Clients::default();
+        let mut started = start(&clients, 1, 0).await;
+        assert!(started.session.send(SessionMsg::Shell(ChannelDetails::default())).await);
+        assert!(started.session.send(SessionMsg::Close).await);
+
+        finished(started.task).await;
+        assert!(clients.lock().await.is_empty());
+        assert!(!written(&mut started.writes).contains(&ChannelWrite::Close));
+        assert!(!started.session.send(SessionMsg::Tick).await);
+    }
+
+    #[tokio::test]
+    async fn eof_closes_the_channel_and_stops_the_actor() {
+        let clients = Clients::default();
+        let mut started = start(&clients, 1, 0).await;
+        assert!(started.session.send(SessionMsg::Shell(ChannelDetails::default())).await);
+        assert!(started.session.send(SessionMsg::Eof).await);
+
+        finished(started.task).await;
+        assert!(clients.lock().await.is_empty());
+        let writes = written(&mut started.writes);
+        assert_eq!(writes[writes.len() - 2..], [ChannelWrite::ExitStatus(0), ChannelWrite::Close]);
+    }
+}
@@ -1,16 +1,28 @@
+use std::sync::Arc;
 use tui_app::App as TuiApp;
 use ratatui::{backend::CrosstermBackend, Terminal, TerminalOptions, Viewport};
 use ratatui::layout::Rect;
 use super::terminal::TerminalHandle;
+use super::session::{ChannelDetails, SharedContext};
+use super::totp::TotpStore;
 
 type SshTerminal = Terminal<CrosstermBackend<TerminalHandle>>;
 
+/// Wrong codes a TOTP setup session may enter before it is closed
+const MAX_TOTP_ATTEMPTS: u32 = 3;
+
 #[derive(Debug)]
 pub struct App {
     pub content: String,
     tui_app: Option<TuiApp>,
     terminal: Option<SshTerminal>,
     mode: AppMode,
+    context: SharedContext,
+    /// Set once the shell starts
+    channel: ChannelDetails,
+    /// Checks the codes typed on a TOTP setup screen
+    totp: Option<Arc<TotpStore>>,
+    totp_failures: u32,
 }
 
 #[derive(Debug)]
@@ -20,16 +32,23 @@ enum AppMode {
 }
 
 impl App {
-    pub fn start() -> Self {
+    pub fn start(context: SharedContext) -> Self {
         Self { 
             content: String::new(),
             tui_app: None,
             terminal: None,
             mode: AppMode::Mock,
+            context,
+            channel: ChannelDetails::default(),
+            totp: None,
+            totp_failures: 0,
         }
     }
     
-    pub fn start_tui(terminal_handle: TerminalHandle) -> Self {
+    /// Guests get the key guide instead of the member screens.
+    pub fn start_tui(terminal_handle: TerminalHandle, context: SharedContext) -> Self {
+        let guest = context.read().guest;
+        let enrolment = context.read().totp_enrolment.clone();
         let backend = CrosstermBackend::new(terminal_handle);
         let options = TerminalOptions {
             viewport: Viewport::Fixed(Rect::default()),
@@ -38,21 +57,38 @@ impl App {
         
         Self {
             content: String::new(),
-            tui_app: Some(TuiApp::new()),
+            tui_app: Some(match enrolment {
+                Some(enrolment) => TuiApp::totp_setup(enrolment.route, enrolment.otpauth_uri),
+                None if guest => TuiApp::guest(),
+                None => TuiApp::new(),
+            }),
             terminal,
             mode: AppMode::Tui,
+            context,
+            channel: ChannelDetails::default(),
+            totp: None,
+            totp_failures: 0,
         }
     }
+
+    /// The setup screen of a session enrolling its key, checking codes
+    /// against `totp`.
+    pub fn start_totp_setup(terminal_handle: TerminalHandle, context: SharedContext, totp: Arc<TotpStore>) -> Self {
+        let mut app = Self::start_tui(terminal_handle, context);
+        app.totp = Some(totp);
+        app
+    }
     
-    pub fn serve(&mut self, route: Option<&str>) {
+    pub fn serve(&mut self) {
         match self.mode {
-            AppMode::Mock => self.serve_mock(route),
+            AppMode::Mock => self.serve_mock(),
             AppMode::Tui => self.serve_tui(),
         }
     }
     
-    fn serve_mock(&mut self, route: Option<&str>) {
-        match route {
+    fn serve_mock(&mut self) {
+        let route = self.context.read().route.clone();
+        match route.as_deref() {
             Some("hello") => self.content = "Shell started! Hello World!\r\n".to_string(),
             Some("discord") => self.content = "Here is the discord link: discord.gg/12345\r\n".to_string(),
             _ => self.content = "Here is the discord link: discord.gg/12345\r\n".to_string(),
@@ -61,6 +97,7 @@ impl App {
     
     fn serve_tui(&mut self) {
         if let (Some(terminal), Some(tui_app)) = (&mut self.terminal, &mut self.tui_app) {
+            tui_app.details = self.context.read().details(&self.channel);
             let _ = terminal.draw(|frame| tui_app.render(frame));
         }
     }
@@ -69,23 +106,59 @@ impl App {
         match self.mode {
             AppMode::Mock => matches!(data, b"q" | b"\x03" | b"\x04"),
             AppMode::Tui => {
-                if let Some(tui_app) = &mut self.tui_app {
-                    if let Some(key_event) = ssh_data_to_key_event(data) {
-                        tui_app.on_key_event(key_event);
-                        return tui_app.input_buffer == "200";
-                    }
+                let Some(tui_app) = &mut self.tui_app else { return false };
+                // A pasted code arrives in one packet
+                let keys: Vec<&[u8]> = if tui_app.totp_setup.is_some() && !data.contains(&0x1b) {
+                    data.chunks(1).collect()
+                } else {
+                    vec![data]
+                };
+                for key_event in keys.into_iter().filter_map(ssh_data_to_key_event) {
+                    tui_app.on_key_event(key_event);
                 }
-                false
+                tui_app.exit_requested()
             }
         }
     }
     
+    /// Checks a code submitted on the TOTP setup screen, which enrols the
+    /// key. True once too many wrong codes were entered.
+    pub async fn confirm_totp(&mut self) -> bool {
+        let (Some(totp), Some(setup)) = (&self.totp, self.tui_app.as_mut().and_then(|t| t.totp_setup.as_mut())) else {
+            return false;
+        };
+        let Some(code) = setup.submitted.take() else { return false };
+        let fingerprint = self.context.read().key_fingerprint.clone().unwrap_or_default();
+        let result = totp.verify(&fingerprint, &code).await;
+        match &result {
+            Ok(()) => log::info!("TOTP enrolment confirmed | {}, route: {}", self.context.read(), setup.route),
+            Err(reason) => {
+                log::info!("TOTP enrolment code rejected | {}, reason: {reason}", self.context.read());
+                self.totp_failures += 1;
+            }
+        }
+        setup.result = Some(result.map_err(|_| "That code did not work, try the next one.".to_string()));
+        self.totp_failures >= MAX_TOTP_ATTEMPTS
+    }
+
     pub fn resize(&mut self, width: u16, height: u16) {
         if let Some(terminal) = &mut self.terminal {
             let rect = Rect { x: 0, y: 0, width, height };
             let _ = terminal.resize(rect);
         }
     }
+
+    pub fn set_channel(&mut self, details: ChannelDetails) {
+        self.channel = details;
+    }
+
+    pub fn is_tui(&self) -> bool {
+        matches!(self.mode, AppMode::Tui)
+    }
+
+    pub fn context(&self) -> &SharedContext {
+        &self.context
+    }
 }
 
 fn ssh_data_to_key_event(data: &[u8]) -> Option<crossterm::event::KeyEvent> {
@@ -95,6 +168,9 @@ fn ssh_data_to_key_event(data: &[u8]) -> Option<crossterm::event::KeyEvent> {
         b"d" => Some(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE)),
         b"D" => Some(KeyEvent::new(KeyCode::Char('D'), KeyModifiers::SHIFT)),
         b"200" => Some(KeyEvent::new(KeyCode::Char('0'), KeyModifiers::NONE)), // Handle sequence
+        b"\r" | b"\n" => Some(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)),
+        b"\x7f" | b"\x08" => Some(KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE)),
+        b"\x1b" => Some(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)),
         _ if data.len() == 1 && data[0].is_ascii() => {
             Some(KeyEvent::new(KeyCode::Char(data[0] as char), KeyModifiers::NONE))
         }
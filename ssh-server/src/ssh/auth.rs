@@ -1,58 +1,449 @@
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use russh::keys::{PublicKey, Algorithm};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, oneshot};
+use serde::{Deserialize, Serialize};
+use russh::keys::{PublicKey, Algorithm, HashAlg};
+use crate::ssh::keypair::write_atomic;
 
-const MAX_LOG_ENTRIES: usize = 1000;
+/// Client key families the policy can allow. FIDO `sk-*` keys are governed
+/// separately by `KeyPolicy::allow_security_keys`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    Ed25519,
+    Ecdsa,
+    Rsa,
+    Dsa,
+}
+
+/// Which client keys `auth_publickey` accepts.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyPolicy {
+    pub allowed_algorithms: Vec<KeyType>,
+    /// Smallest RSA modulus accepted, in bits
+    pub min_rsa_bits: usize,
+    /// Accept `sk-ssh-ed25519@openssh.com` and `sk-ecdsa-sha2-nistp256@openssh.com`
+    pub allow_security_keys: bool,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_algorithms: vec![KeyType::Ed25519, KeyType::Ecdsa, KeyType::Rsa],
+            min_rsa_bits: 2048,
+            allow_security_keys: true,
+        }
+    }
+}
+
+/// Usernames kept per key; a client cycling through names only has its
+/// first ones recorded.
+const MAX_USERNAMES: usize = 32;
+/// The file is compacted once it holds this many lines more than there are
+/// keys.
+const COMPACT_SLACK: usize = 1024;
+
+/// What is known about one client key. Stored one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub fingerprint: String,
+    pub algorithm: String,
+    pub comment: String,
+    pub usernames: BTreeSet<String>,
+    /// Address of the most recent attempt
+    pub source_ip: Option<IpAddr>,
+    /// Unix timestamps
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub attempts: u64,
+}
+
+impl KeyRecord {
+    /// One-off keys are evicted before keys that came back, which keeps a
+    /// burst of scanners from pushing out returning members.
+    fn eviction_rank(&self) -> (bool, u64) {
+        (self.attempts > 1, self.last_seen)
+    }
+}
+
+#[derive(Debug)]
+struct Store {
+    /// Changed through `insert` and `remove` only, which keep the indexes
+    records: HashMap<String, KeyRecord>,
+    /// Fingerprints by eviction rank, the next to evict first
+    by_rank: BTreeSet<((bool, u64), String)>,
+    /// Fingerprints by `last_seen`, the next to expire first
+    by_age: BTreeSet<(u64, String)>,
+    /// Append-only JSONL file; each update appends the whole record and the
+    /// last line for a fingerprint wins
+    writer: Option<mpsc::Sender<LogWrite>>,
+    /// Lines in the file, compacted or appended
+    lines: usize,
+    capacity: usize,
+    /// Records not seen for this many seconds are dropped; 0 keeps them
+    ttl: u64,
+    evicted_capacity: u64,
+    evicted_expired: u64,
+}
+
+impl Store {
+    fn expired(&self, record: &KeyRecord, now: u64) -> bool {
+        self.ttl > 0 && now.saturating_sub(record.last_seen) >= self.ttl
+    }
+
+    fn insert(&mut self, record: KeyRecord) {
+        self.remove(&record.fingerprint);
+        self.by_rank.insert((record.eviction_rank(), record.fingerprint.clone()));
+        self.by_age.insert((record.last_seen, record.fingerprint.clone()));
+        self.records.insert(record.fingerprint.clone(), record);
+    }
+
+    fn remove(&mut self, fingerprint: &str) -> Option<KeyRecord> {
+        let record = self.records.remove(fingerprint)?;
+        self.by_rank.remove(&(record.eviction_rank(), record.fingerprint.clone()));
+        self.by_age.remove(&(record.last_seen, record.fingerprint.clone()));
+        Some(record)
+    }
+
+    /// Drops expired records, then the lowest ranked ones until there is
+    /// room for one more.
+    fn evict(&mut self, now: u64) {
+        let mut expired = 0;
+        while let Some((last_seen, fingerprint)) = self.by_age.first().cloned()
+            && self.ttl > 0
+            && now.saturating_sub(last_seen) >= self.ttl
+        {
+            self.remove(&fingerprint);
+            expired += 1;
+        }
+
+        let mut over_capacity = 0;
+        while self.records.len() >= self.capacity
+            && let Some((_, fingerprint)) = self.by_rank.first().cloned()
+        {
+            self.remove(&fingerprint);
+            over_capacity += 1;
+        }
+
+        if expired + over_capacity > 0 {
+            self.evicted_expired += expired as u64;
+            self.evicted_capacity += over_capacity as u64;
+            log::debug!(
+                "Auth log evicted {} expired and {} over capacity (totals: {} expired, {} over capacity)",
+                expired, over_capacity, self.evicted_expired, self.evicted_capacity,
+            );
+        }
+    }
+}
+
+/// Writes to the log file, done in order by the writer thread so file I/O
+/// never runs on the runtime or under the store lock.
+#[derive(Debug)]
+enum LogWrite {
+    Append(String),
+    /// Replace the file with these records
+    Compact(Vec<u8>),
+    /// Answered once everything sent before has been written
+    Flush(oneshot::Sender<()>),
+}
+
+fn spawn_writer(path: PathBuf, mut file: File) -> mpsc::Sender<LogWrite> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for write in receiver {
+            match write {
+                LogWrite::Append(line) => {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        log::warn!("Failed to append to auth log: {}", e);
+                    }
+                }
+                LogWrite::Compact(records) => {
+                    let reopened = write_atomic(&path, &records, 0o600).and_then(|()| open_append(&path));
+                    match reopened {
+                        Ok(compacted) => file = compacted,
+                        Err(e) => log::warn!("Failed to compact auth log: {}", e),
+                    }
+                }
+                LogWrite::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    sender
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new().append(true).mode(0o600).open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
 
-#[derive(Debug, Clone, Default)]
+/// One JSON line per record.
+fn serialize<'a>(records: impl Iterator<Item = &'a KeyRecord>) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut bytes, record).map_err(|e| e.to_string())?;
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuthLogStats {
+    pub keys: usize,
+    pub evicted_capacity: u64,
+    pub evicted_expired: u64,
+}
+
+#[derive(Debug, Clone)]
 pub struct AuthLog {
-    entries: Arc<Mutex<HashSet<String>>>,
+    store: Arc<Mutex<Store>>,
 }
 
 impl AuthLog {
-    pub fn new() -> Self {
-        Self::default()
+    /// An in-memory log, or one backed by the JSONL file at `path`. Holds at
+    /// most `capacity` keys and forgets keys not seen for `ttl` (zero keeps
+    /// them). The file is compacted to the records kept on open, and again
+    /// whenever appended updates outgrow it.
+    pub fn open(path: Option<&Path>, capacity: usize, ttl: Duration) -> Result<Self, String> {
+        let mut store = Store {
+            records: HashMap::new(),
+            by_rank: BTreeSet::new(),
+            by_age: BTreeSet::new(),
+            writer: None,
+            lines: 0,
+            capacity,
+            ttl: ttl.as_secs(),
+            evicted_capacity: 0,
+            evicted_expired: 0,
+        };
+        let Some(path) = path else { return Ok(Self { store: Arc::new(Mutex::new(store)) }) };
+
+        let mut records = HashMap::new();
+        if path.exists() {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                match serde_json::from_str::<KeyRecord>(line) {
+                    Ok(record) => { records.insert(record.fingerprint.clone(), record); }
+                    Err(e) => log::warn!("Skipping {}:{}: {}", path.display(), i + 1, e),
+                }
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mut kept: Vec<KeyRecord> = records.into_values().filter(|r| !store.expired(r, now)).collect();
+        kept.sort_by_key(|r| std::cmp::Reverse(r.eviction_rank()));
+        kept.truncate(capacity);
+
+        write_atomic(path, &serialize(kept.iter())?, 0o600)?;
+        let file = open_append(path)?;
+
+        log::info!("Auth log {} holds {} key(s)", path.display(), kept.len());
+        store.lines = kept.len();
+        for record in kept {
+            store.insert(record);
+        }
+        store.writer = Some(spawn_writer(path.to_path_buf(), file));
+        Ok(Self { store: Arc::new(Mutex::new(store)) })
     }
 
-    pub async fn eval_key(&self, key: &PublicKey) -> bool {
-        let key_type = key.key_data().algorithm();
-        match key_type {
-            Algorithm::Ed25519 => true,
-            Algorithm::Dsa => false,
-            Algorithm::Ecdsa { curve: _ } => false,
-            Algorithm::Rsa { hash: _ } => false,
-            Algorithm::SkEcdsaSha2NistP256 => false,
-            Algorithm::SkEd25519 => false,
-            Algorithm::Other(_) => false,
-            _ => false
+    /// Checks `key` against the policy, returning why it is refused.
+    pub async fn eval_key(&self, key: &PublicKey, policy: &KeyPolicy) -> Result<(), String> {
+        let algorithm = key.key_data().algorithm();
+        let key_type = match algorithm {
+            Algorithm::Ed25519 => KeyType::Ed25519,
+            Algorithm::Ecdsa { curve: _ } => KeyType::Ecdsa,
+            Algorithm::Rsa { hash: _ } => KeyType::Rsa,
+            Algorithm::Dsa => KeyType::Dsa,
+            Algorithm::SkEcdsaSha2NistP256 | Algorithm::SkEd25519 => {
+                return if policy.allow_security_keys {
+                    Ok(())
+                } else {
+                    Err(format!("security keys are not allowed ({algorithm})"))
+                };
+            }
+            _ => return Err(format!("unsupported key type {algorithm}")),
+        };
+
+        if !policy.allowed_algorithms.contains(&key_type) {
+            return Err(format!("key type {algorithm} is not allowed"));
         }
+        if let Some(rsa) = key.key_data().rsa() {
+            let bits = rsa.key_size() as usize;
+            if bits < policy.min_rsa_bits {
+                return Err(format!("RSA key is {bits} bits, minimum is {}", policy.min_rsa_bits));
+            }
+        }
+        Ok(())
     }
 
-    pub async fn record_key(&self, user: &str, key: &PublicKey) -> bool {
-        let fingerprint = key.fingerprint(Default::default()).to_string();
-        let entry = format!("{fingerprint}:{user}");
+    /// Records an attempt with `key` and returns whether the key is new.
+    pub async fn record_key(&self, user: &str, key: &PublicKey, source_ip: Option<IpAddr>) -> bool {
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        let mut store = self.store.lock().await;
+        let known = store.records.get(&fingerprint).is_some_and(|r| !store.expired(r, now));
+        if !known {
+            store.remove(&fingerprint);
+            store.evict(now);
+        }
 
-        let mut entries = self.entries.lock().await;
-        self.maybe_clear_entries(&mut entries);
+        let mut record = store.remove(&fingerprint).unwrap_or_else(|| KeyRecord {
+            fingerprint: fingerprint.clone(),
+            algorithm: key.algorithm().to_string(),
+            comment: String::new(),
+            usernames: BTreeSet::new(),
+            source_ip: None,
+            first_seen: now,
+            last_seen: now,
+            attempts: 0,
+        });
+        let is_new = record.attempts == 0;
+        if !key.comment().is_empty() {
+            record.comment = key.comment().to_string();
+        }
+        if record.usernames.len() < MAX_USERNAMES {
+            record.usernames.insert(user.to_string());
+        }
+        record.source_ip = source_ip.or(record.source_ip);
+        record.last_seen = now;
+        record.attempts += 1;
 
-        if entries.insert(entry.clone()) {
-            log::info!("New public key login attempt: {entry}");
-            true // New key, can persist to DB
+        if is_new {
+            log::info!("New public key login attempt: {fingerprint}:{user}");
         } else {
-            false // Already logged
+            log::info!(
+                "Returning key {fingerprint}:{user}, attempt {} since {}", record.attempts, record.first_seen
+            );
+        }
+
+        let line = serde_json::to_string(&record);
+        store.insert(record);
+        let Some(writer) = store.writer.clone() else { return is_new };
+        match line {
+            Ok(line) => {
+                let _ = writer.send(LogWrite::Append(line));
+                store.lines += 1;
+            }
+            Err(e) => log::warn!("Failed to append to auth log: {}", e),
         }
+        if store.lines > store.records.len() + COMPACT_SLACK {
+            match serialize(store.records.values()) {
+                Ok(records) => {
+                    let _ = writer.send(LogWrite::Compact(records));
+                    log::debug!("Compacting auth log to {} key(s)", store.records.len());
+                    store.lines = store.records.len();
+                }
+                Err(e) => log::warn!("Failed to compact auth log: {}", e),
+            }
+        }
+        is_new
     }
 
-    pub async fn _all_entries(&self) -> Vec<String> {
-        let entries = self.entries.lock().await;
-        entries.iter().cloned().collect()
+    /// Waits until every update so far is in the file.
+    pub async fn flush(&self) {
+        let Some(writer) = self.store.lock().await.writer.clone() else { return };
+        let (done, written) = oneshot::channel();
+        if writer.send(LogWrite::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
     }
 
-    fn maybe_clear_entries(&self, entries: &mut HashSet<String>) {
-        if entries.len() > MAX_LOG_ENTRIES {
-            entries.clear();
-            log::warn!("Auth log cleared: exceeded {MAX_LOG_ENTRIES} entries.");
+    /// The record kept for `key`, if it has been seen before.
+    pub async fn history(&self, key: &PublicKey) -> Option<KeyRecord> {
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let store = self.store.lock().await;
+        store.records.get(&fingerprint).filter(|r| !store.expired(r, now)).cloned()
+    }
+
+    pub async fn stats(&self) -> AuthLogStats {
+        let store = self.store.lock().await;
+        AuthLogStats {
+            keys: store.records.len(),
+            evicted_capacity: store.evicted_capacity,
+            evicted_expired: store.evicted_expired,
         }
     }
+
+    pub async fn _all_entries(&self) -> Vec<KeyRecord> {
+        let store = self.store.lock().await;
+        store.records.values().cloned().collect()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh::keys::ssh_key::public::{Ed25519PublicKey, KeyData};
+
+    fn key(n: u32) -> PublicKey {
+        let mut bytes = [0; 32];
+        bytes[..4].copy_from_slice(&n.to_be_bytes());
+        PublicKey::new(KeyData::Ed25519(Ed25519PublicKey(bytes)), "")
+    }
+
+    fn record(fingerprint: &str, attempts: u64, last_seen: u64) -> KeyRecord {
+        KeyRecord {
+            fingerprint: fingerprint.to_string(),
+            algorithm: "ssh-ed25519".to_string(),
+            comment: String::new(),
+            usernames: BTreeSet::new(),
+            source_ip: None,
+            first_seen: last_seen,
+            last_seen,
+            attempts,
+        }
+    }
+
+    #[tokio::test]
+    async fn returning_keys_survive_a_burst() {
+        let log = AuthLog::open(None, 10, Duration::ZERO).unwrap();
+        for n in 0..3 {
+            log.record_key("member", &key(n), None).await;
+            log.record_key("member", &key(n), None).await;
+        }
+        for n in 100..200 {
+            log.record_key("scanner", &key(n), None).await;
+        }
+
+        for n in 0..3 {
+            assert_eq!(log.history(&key(n)).await.map(|r| r.attempts), Some(2));
+        }
+        assert!(log.history(&key(199)).await.is_some());
+        assert!(log.history(&key(100)).await.is_none());
+        let stats = log.stats().await;
+        assert_eq!((stats.keys, stats.evicted_capacity, stats.evicted_expired), (10, 93, 0));
+        let store = log.store.lock().await;
+        assert_eq!((store.by_rank.len(), store.by_age.len()), (10, 10));
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_counted() {
+        let log = AuthLog::open(None, 10, Duration::from_secs(60)).unwrap();
+        let mut store = log.store.lock().await;
+        store.insert(record("old", 5, 1000));
+        store.insert(record("older", 1, 900));
+        store.insert(record("recent", 1, 1050));
+
+        store.evict(1060);
+        assert_eq!(store.records.keys().collect::<Vec<_>>(), ["recent"]);
+        assert_eq!((store.evicted_expired, store.evicted_capacity), (2, 0));
+        assert_eq!((store.by_rank.len(), store.by_age.len()), (1, 1));
+
+        store.evict(1110);
+        assert!(store.records.is_empty());
+        assert_eq!(store.evicted_expired, 3);
+    }
 }
@@ -0,0 +1,342 @@
+use std::{fs, net::IpAddr, path::{Path, PathBuf}};
+use std::time::{SystemTime, UNIX_EPOCH};
+use russh::keys::PublicKey;
+
+/// The OpenSSH options honoured on an `authorized_keys` line. Options we do
+/// not act on are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// `from="..."` patterns the client address must match
+    pub from: Option<Vec<String>>,
+    /// `command="..."`, used as the route whatever username was given
+    pub command: Option<String>,
+    /// `expiry-time="..."` as a unix timestamp
+    pub expiry_time: Option<u64>,
+    pub no_pty: bool,
+    pub restrict: bool,
+    /// `pty`, which lifts the pty ban that `restrict` implies
+    pub pty: bool,
+}
+
+impl KeyOptions {
+    /// Parses the comma-separated options in front of a key. Values may be
+    /// quoted to hold spaces and commas, with `\"` for a literal quote.
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut options = Self::default();
+        for option in split_unquoted(text, |c| c == ',') {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(unquote(value)?)),
+                None => (option, None),
+            };
+            let value = value.as_deref();
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("from", Some(v)) => options.from = Some(v.split(',').map(str::to_string).collect()),
+                ("command", Some(v)) => options.command = Some(v.to_string()),
+                ("expiry-time", Some(v)) => options.expiry_time = Some(parse_expiry_time(v)?),
+                ("no-pty", None) => options.no_pty = true,
+                ("restrict", None) => options.restrict = true,
+                ("pty", None) => options.pty = true,
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+
+    /// Whether a pty may be allocated for the session.
+    pub fn pty_allowed(&self) -> bool {
+        !self.no_pty && (!self.restrict || self.pty)
+    }
+
+    /// Checks the `from=` and `expiry-time=` restrictions for a login from
+    /// `peer`. An IPv4-mapped IPv6 address (from a dual-stack listener) is
+    /// matched as the IPv4 address it carries.
+    pub fn permits(&self, peer: Option<IpAddr>) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+        self.permits_at(peer, now)
+    }
+
+    fn permits_at(&self, peer: Option<IpAddr>, now: u64) -> Result<(), String> {
+        if let Some(expiry) = self.expiry_time && now >= expiry {
+            return Err("key has expired".to_string());
+        }
+        if let Some(patterns) = &self.from {
+            let Some(peer) = peer else {
+                return Err("from= is set but the client address is unknown".to_string());
+            };
+            if !match_address_list(peer.to_canonical(), patterns) {
+                return Err(format!("{} does not match from=\"{}\"", peer, patterns.join(",")));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    pub key: PublicKey,
+    pub options: KeyOptions,
+}
+
+/// Keys read from one or more `authorized_keys` files.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    entries: Vec<AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    pub fn load(paths: &[PathBuf]) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for path in paths {
+            entries.extend(Self::read_file(path)?);
+        }
+        Ok(Self { entries })
+    }
+
+    fn read_file(path: &Path) -> Result<Vec<AuthorizedKey>, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| parse_line(line).map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The first entry for `key`, comparing key material only.
+    pub fn find(&self, key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.entries.iter().find(|entry| entry.key.key_data() == key.key_data())
+    }
+}
+
+/// One `[options] key [comment]` line. Lines that do not start with a key
+/// start with options, which end at the first space outside quotes.
+fn parse_line(line: &str) -> Result<AuthorizedKey, String> {
+    if let Ok(key) = PublicKey::from_openssh(line) {
+        return Ok(AuthorizedKey { key, options: KeyOptions::default() });
+    }
+    let mut parts = split_unquoted(line, |c| c == ' ' || c == '\t');
+    let options = parts.next().unwrap_or_default();
+    let key = line[options.len()..].trim_start();
+    let key = PublicKey::from_openssh(key).map_err(|e| e.to_string())?;
+    Ok(AuthorizedKey { key, options: KeyOptions::parse(options)? })
+}
+
+/// Splits `text` on the characters matching `separator` that are not
+/// inside double quotes.
+fn split_unquoted(text: &str, separator: impl Fn(char) -> bool) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    text.split(move |c| {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ => return !quoted && separator(c),
+        }
+        false
+    })
+}
+
+/// An option value, without its quotes and with `\"` unescaped.
+fn unquote(value: &str) -> Result<String, String> {
+    let Some(inner) = value.strip_prefix('"') else { return Ok(value.to_string()) };
+    let inner = inner.strip_suffix('"').ok_or_else(|| format!("unterminated quote in {value:?}"))?;
+    Ok(inner.replace("\\\"", "\""))
+}
+
+/// OpenSSH `from=` semantics: a matching negated pattern (`!pattern`) denies
+/// outright, otherwise any positive match allows. Patterns are wildcards
+/// (`*`, `?`) or CIDR blocks; host names are not resolved.
+fn match_address_list(peer: IpAddr, patterns: &[String]) -> bool {
+    let mut allowed = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, pattern.as_str()),
+        };
+        if match_address(peer, pattern) {
+            if negated {
+                return false;
+            }
+            allowed = true;
+        }
+    }
+    allowed
+}
+
+fn match_address(peer: IpAddr, pattern: &str) -> bool {
+    if let Some((net, len)) = pattern.split_once('/') {
+        let (Ok(net), Ok(len)) = (net.parse::<IpAddr>(), len.parse::<u32>()) else { return false };
+        return match (peer, net) {
+            (IpAddr::V4(p), IpAddr::V4(n)) if len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                u32::from(p) & mask == u32::from(n) & mask
+            }
+            (IpAddr::V6(p), IpAddr::V6(n)) if len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                u128::from(p) & mask == u128::from(n) & mask
+            }
+            _ => false,
+        };
+    }
+    wildcard_match(pattern.as_bytes(), peer.to_string().as_bytes())
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], text) || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => wildcard_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Parses `YYYYMMDD[HHMM[SS]]` with an optional trailing `Z`. The time is
+/// always taken as UTC.
+fn parse_expiry_time(value: &str) -> Result<u64, String> {
+    let digits = value.strip_suffix(['Z', 'z']).unwrap_or(value);
+    let invalid = || format!("Invalid expiry-time: {:?}", value);
+    if !matches!(digits.len(), 8 | 12 | 14) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    // Missing time fields count as zero; every field is known to be digits.
+    let field = |range: std::ops::Range<usize>| digits.get(range).map_or(0, |f| f.parse::<u64>().unwrap_or(0));
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23 || minute > 59 || second > 59
+    {
+        return Err(invalid());
+    }
+    Ok(days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 for a Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOXvupY38GFBvdrrsiMkDEYYVU+qB4irJh3uMBo33T7R";
+
+    fn options(options: &str) -> KeyOptions {
+        parse_line(&format!("{options} {KEY}")).unwrap().options
+    }
+
+    fn from(patterns: &str, peer: &str) -> bool {
+        let patterns: Vec<String> = patterns.split(',').map(str::to_string).collect();
+        match_address_list(peer.parse::<IpAddr>().unwrap().to_canonical(), &patterns)
+    }
+
+    #[test]
+    fn negated_patterns_deny_outright() {
+        assert!(!from("!10.0.0.1,10.0.0.*", "10.0.0.1"));
+        assert!(from("!10.0.0.1,10.0.0.*", "10.0.0.2"));
+        assert!(!from("10.0.0.*,!10.0.0.1", "10.0.0.1"));
+        assert!(!from("!10.0.0.1,10.0.0.*", "10.1.0.1"));
+        assert!(!from("!10.0.0.1", "192.0.2.1"));
+        assert!(!from("!10.0.0.0/8,0.0.0.0/0", "10.20.30.40"));
+    }
+
+    #[test]
+    fn cidr_prefixes() {
+        assert!(from("0.0.0.0/0", "203.0.113.9"));
+        assert!(from("192.0.2.7/32", "192.0.2.7"));
+        assert!(!from("192.0.2.7/32", "192.0.2.8"));
+        assert!(from("192.0.2.0/24", "192.0.2.200"));
+        assert!(!from("192.0.2.0/33", "192.0.2.7"));
+        assert!(from("::/0", "2001:db8::1"));
+        assert!(from("2001:db8::1/128", "2001:db8::1"));
+        assert!(!from("2001:db8::1/128", "2001:db8::2"));
+        assert!(!from("::/0", "192.0.2.7"));
+        assert!(!from("0.0.0.0/0", "2001:db8::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_as_ipv4() {
+        assert!(from("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(from("10.1.2.*", "::ffff:10.1.2.3"));
+        assert!(!from("!10.1.2.3,*", "::ffff:10.1.2.3"));
+        let peer = "::ffff:10.1.2.3".parse().ok();
+        assert!(options(r#"from="10.1.2.3""#).permits_at(peer, 0).is_ok());
+        assert!(options(r#"from="192.0.2.1""#).permits_at(peer, 0).is_err());
+    }
+
+    #[test]
+    fn restrict_bans_a_pty_unless_pty_is_given() {
+        assert!(options("no-agent-forwarding").pty_allowed());
+        assert!(!options("restrict").pty_allowed());
+        assert!(options("restrict,pty").pty_allowed());
+        assert!(!options("no-pty").pty_allowed());
+        assert!(!options("restrict,pty,no-pty").pty_allowed());
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(options(r#"command="admin""#).command.as_deref(), Some("admin"));
+        assert_eq!(options(r#"restrict,command="echo hi, there""#).command.as_deref(), Some("echo hi, there"));
+        assert_eq!(options(r#"command="echo \"hi\"""#).command.as_deref(), Some(r#"echo "hi""#));
+        let patterns = options(r#"from="10.0.0.1,!10.0.0.2",no-pty"#).from.unwrap();
+        assert_eq!(patterns, ["10.0.0.1", "!10.0.0.2"]);
+        assert!(parse_line(&format!(r#"command="admin {KEY}"#)).is_err());
+    }
+
+    #[test]
+    fn lines_with_and_without_options_or_comments() {
+        let plain = parse_line(KEY).unwrap();
+        assert_eq!(plain.options, KeyOptions::default());
+        assert_eq!(parse_line(&format!("{KEY} alice at work")).unwrap().key.comment().as_str_lossy(), "alice at work");
+        let line = parse_line(&format!(r#"command="echo hi" {KEY} alice"#)).unwrap();
+        assert_eq!(line.key.key_data(), plain.key.key_data());
+        assert_eq!(line.key.comment().as_str_lossy(), "alice");
+        assert!(parse_line(&format!("restrict\t{KEY}")).unwrap().options.restrict);
+        assert!(parse_line("restrict ssh-ed25519 AAAA").is_err());
+    }
+
+    #[test]
+    fn expiry_is_exclusive() {
+        let options = options(r#"expiry-time="20250101""#);
+        let expiry = options.expiry_time.unwrap();
+        assert_eq!(expiry, 1_735_689_600);
+        assert!(options.permits_at(None, expiry - 1).is_ok());
+        assert!(options.permits_at(None, expiry).is_err());
+    }
+
+    #[test]
+    fn expiry_time_formats() {
+        assert_eq!(parse_expiry_time("20250101").unwrap(), 1_735_689_600);
+        assert_eq!(parse_expiry_time("202501010130").unwrap(), 1_735_689_600 + 5400);
+        assert_eq!(parse_expiry_time("20250101013005Z").unwrap(), 1_735_689_600 + 5405);
+        assert_eq!(parse_expiry_time("20240229").unwrap(), 1_709_164_800);
+        assert_eq!(parse_expiry_time("20000229").unwrap(), 951_782_400);
+        for invalid in ["20250231", "20230229", "21000229", "20250431", "20251301", "20250100", "202501012400", "2025010112", "2025-01-01", "19691231"] {
+            assert!(parse_expiry_time(invalid).is_err(), "{invalid} was accepted");
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use crate::ssh::actor::SessionHandle;
+use crate::ssh::session::ChannelDetails;
+
+/// Where a session channel is in its life: opened → pty → running (shell
+/// or exec) → closing (client EOF) → closed. The pty step is optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    Opened,
+    Pty,
+    Running,
+    Closing,
+    Closed,
+}
+
+/// What the client asked of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelEvent {
+    Pty,
+    Env,
+    WindowChange,
+    Shell,
+    Exec,
+    Data,
+    Eof,
+    Close,
+}
+
+impl ChannelState {
+    /// The state after `event`, or why the request is out of order.
+    pub fn next(self, event: ChannelEvent) -> Result<Self, String> {
+        use ChannelEvent as E;
+        use ChannelState as S;
+        match (self, event) {
+            (S::Opened, E::Pty) => Ok(S::Pty),
+            (S::Opened | S::Pty, E::Env) => Ok(self),
+            (S::Opened | S::Pty, E::Shell | E::Exec) => Ok(S::Running),
+            (S::Pty | S::Running | S::Closing, E::WindowChange) => Ok(self),
+            (S::Running, E::Data) => Ok(S::Running),
+            (S::Opened | S::Pty | S::Running, E::Eof) => Ok(S::Closing),
+            (S::Opened | S::Pty | S::Running | S::Closing, E::Close) => Ok(S::Closed),
+            _ => Err(format!("{event:?} request while {self}")),
+        }
+    }
+}
+
+impl fmt::Display for ChannelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Opened => "opened",
+            Self::Pty => "pty",
+            Self::Running => "running",
+            Self::Closing => "closing",
+            Self::Closed => "closed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A session channel as the connection's handler tracks it.
+#[derive(Debug, Clone)]
+pub struct SessionChannel {
+    pub state: ChannelState,
+    /// Mailbox of the actor owning the channel's app
+    pub session: SessionHandle,
+    /// Collected until the shell or exec request hands them to the app
+    pub details: ChannelDetails,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ChannelEvent as E;
+    use super::ChannelState as S;
+
+    const STATES: [ChannelState; 5] = [S::Opened, S::Pty, S::Running, S::Closing, S::Closed];
+    const EVENTS: [ChannelEvent; 8] = [E::Pty, E::Env, E::WindowChange, E::Shell, E::Exec, E::Data, E::Eof, E::Close];
+
+    /// Every transition `next` allows; all other pairs are refused.
+    const ALLOWED: [(ChannelState, ChannelEvent, ChannelState); 18] = [
+        (S::Opened, E::Pty, S::Pty),
+        (S::Opened, E::Env, S::Opened),
+        (S::Pty, E::Env, S::Pty),
+        (S::Opened, E::Shell, S::Running),
+        (S::Opened, E::Exec, S::Running),
+        (S::Pty, E::Shell, S::Running),
+        (S::Pty, E::Exec, S::Running),
+        (S::Pty, E::WindowChange, S::Pty),
+        (S::Running, E::WindowChange, S::Running),
+        (S::Closing, E::WindowChange, S::Closing),
+        (S::Running, E::Data, S::Running),
+        (S::Opened, E::Eof, S::Closing),
+        (S::Pty, E::Eof, S::Closing),
+        (S::Running, E::Eof, S::Closing),
+        (S::Opened, E::Close, S::Closed),
+        (S::Pty, E::Close, S::Closed),
+        (S::Running, E::Close, S::Closed),
+        (S::Closing, E::Close, S::Closed),
+    ];
+
+    #[test]
+    fn allowed_transitions() {
+        for (state, event, after) in ALLOWED {
+            assert_eq!(state.next(event), Ok(after), "{event:?} while {state}");
+        }
+    }
+
+    #[test]
+    fn refused_transitions() {
+        let mut refused = 0;
+        for state in STATES {
+            for event in EVENTS {
+                if ALLOWED.iter().any(|&(s, e, _)| (s, e) == (state, event)) {
+                    continue;
+                }
+                assert_eq!(state.next(event), Err(format!("{event:?} request while {state}")));
+                refused += 1;
+            }
+        }
+        assert_eq!(refused, STATES.len() * EVENTS.len() - ALLOWED.len());
+    }
+}
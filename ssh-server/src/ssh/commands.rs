@@ -0,0 +1,98 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use tui_app::components::welcome::INVITE_LINK;
+use crate::ssh::session::{ChannelDetails, SessionContext};
+
+/// What `ssh host <command>` can run, with its help text.
+const COMMANDS: &[(&str, &str)] = &[
+    ("invite", "print the community invite link (members only)"),
+    ("info", "show the details of this connection"),
+    ("whoami", "show who you are logged in as"),
+    ("version", "show the server version"),
+    ("help", "list these commands"),
+];
+
+/// What an exec request printed and how it ended.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: u32,
+}
+
+/// Runs an exec request's command line, e.g. `info --json`. With `--json`
+/// the output, errors included, is one JSON object on stdout.
+pub fn run(line: &str, context: &SessionContext, channel: &ChannelDetails) -> CommandOutput {
+    let (flags, words): (Vec<&str>, Vec<&str>) = line.split_whitespace().partition(|w| *w == "--json");
+    let json = !flags.is_empty();
+    match dispatch(&words, context, channel) {
+        Ok((value, _)) if json => CommandOutput { stdout: format!("{value}\n"), stderr: String::new(), exit_status: 0 },
+        Ok((_, text)) => CommandOutput { stdout: text, stderr: String::new(), exit_status: 0 },
+        Err((exit_status, message)) if json => CommandOutput {
+            stdout: format!("{}\n", json!({ "error": message })),
+            stderr: String::new(),
+            exit_status,
+        },
+        Err((exit_status, message)) => CommandOutput { stdout: String::new(), stderr: format!("{message}\n"), exit_status },
+    }
+}
+
+/// The JSON and text forms of a command's output, or its exit status and
+/// error message.
+fn dispatch(words: &[&str], context: &SessionContext, channel: &ChannelDetails) -> Result<(Value, String), (u32, String)> {
+    if let Some(enrolment) = &context.totp_enrolment {
+        return Err((1, format!("set up two-factor authentication for {} first: log in with a terminal (ssh -t)", enrolment.route)));
+    }
+    match words {
+        [] | ["help"] => Ok(help()),
+        ["invite"] if context.guest => Err((1, "invite links are for members, log in with an SSH key".to_string())),
+        ["invite"] => Ok((json!({ "invite": INVITE_LINK }), format!("{INVITE_LINK}\n"))),
+        ["info"] => Ok(info(context, channel)),
+        ["whoami"] => {
+            let user = context.username.clone().unwrap_or_default();
+            let value = json!({ "user": user, "route": context.route, "guest": context.guest });
+            Ok((value, format!("{user}\n")))
+        }
+        ["version"] => {
+            let (name, version) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            Ok((json!({ "name": name, "version": version }), format!("{name} {version}\n")))
+        }
+        [name, ..] if COMMANDS.iter().any(|(n, _)| n == name) => Err((2, format!("{name} takes no arguments"))),
+        [name, ..] => Err((127, format!("unknown command {name:?}, try `help`"))),
+    }
+}
+
+fn help() -> (Value, String) {
+    let value = json!({
+        "commands": COMMANDS.iter()
+            .map(|(name, description)| json!({ "name": name, "description": description }))
+            .collect::<Vec<_>>(),
+    });
+    let mut text = String::from("usage: ssh <host> <command> [--json]\n\n");
+    for (name, description) in COMMANDS {
+        text.push_str(&format!("  {name:<8} {description}\n"));
+    }
+    (value, text)
+}
+
+fn info(context: &SessionContext, channel: &ChannelDetails) -> (Value, String) {
+    let unix = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let value = json!({
+        "id": context.id,
+        "user": context.username,
+        "route": context.route,
+        "guest": context.guest,
+        "key_algorithm": context.key_algorithm,
+        "key_fingerprint": context.key_fingerprint,
+        "peer": context.peer.map(|p| p.to_string()),
+        "client_version": context.client_version,
+        "term": channel.term,
+        "env": channel.env,
+        "connected_at": unix(context.connected_at),
+        "authenticated_at": context.authenticated_at.map(unix),
+    });
+    let text = context.details(channel).iter()
+        .map(|(label, value)| format!("{label}: {value}\n"))
+        .collect();
+    (value, text)
+}
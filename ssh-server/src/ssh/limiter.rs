@@ -0,0 +1,379 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fs;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use crate::ssh::keypair::write_atomic;
+
+/// Peers tracked at most. Idle ones are pruned first, then the least
+/// recently seen are dropped down to `EVICT_TO`.
+const MAX_TRACKED: usize = 10_000;
+const EVICT_TO: usize = MAX_TRACKED - MAX_TRACKED / 10;
+const MINUTE: Duration = Duration::from_secs(60);
+/// Longest delay before rejecting an authentication.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// fail2ban-style limits applied per client IP.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// New connections accepted per IP per minute, 0 disables
+    pub max_connections_per_minute: usize,
+    /// Failed authentications within `find_time` that get an IP banned, 0 disables bans
+    pub max_auth_failures: usize,
+    /// Seconds over which failures are counted
+    pub find_time: u64,
+    /// Seconds of the first ban; each further ban doubles it up to `max_ban_time`
+    pub ban_time: u64,
+    pub max_ban_time: u64,
+    /// Double the auth rejection delay for every recent failure from the same IP
+    pub backoff: bool,
+    /// Keeps bans across restarts when set
+    pub ban_list_path: Option<PathBuf>,
+    /// IPv6 peers are tracked per network of this prefix length, since one
+    /// host usually holds a whole /64
+    pub ipv6_prefix: u8,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            max_connections_per_minute: 30,
+            max_auth_failures: 10,
+            find_time: 600,
+            ban_time: 600,
+            max_ban_time: 86_400,
+            backoff: true,
+            ban_list_path: None,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    last_seen: Instant,
+    connections: VecDeque<Instant>,
+    failures: VecDeque<Instant>,
+    banned_until: Option<SystemTime>,
+    /// Bans so far, for the exponential ban time
+    bans: u32,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self { last_seen: Instant::now(), connections: VecDeque::new(), failures: VecDeque::new(), banned_until: None, bans: 0 }
+    }
+}
+
+impl Peer {
+    fn banned(&self, now: SystemTime) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn idle(&self, now: SystemTime) -> bool {
+        self.connections.is_empty() && self.failures.is_empty() && !self.banned(now)
+    }
+}
+
+/// Per-IP connection and authentication failure tracking, shared by every
+/// listener. IPv4-mapped IPv6 addresses count as the IPv4 address, and
+/// other IPv6 addresses as their `ipv6_prefix` network.
+#[derive(Debug)]
+pub struct Limiter {
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+    ban_list: Option<PathBuf>,
+}
+
+impl Limiter {
+    /// Loads the bans still in effect from `ban_list`, one `ip until bans`
+    /// line each with `until` in unix seconds.
+    pub fn open(ban_list: Option<&Path>) -> Result<Self, String> {
+        let mut peers = HashMap::new();
+        if let Some(path) = ban_list.filter(|p| p.exists()) {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let now = SystemTime::now();
+            for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                let mut fields = line.split_whitespace();
+                let parsed = (|| {
+                    let ip = fields.next()?.parse::<IpAddr>().ok()?.to_canonical();
+                    let until = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+                    let bans = fields.next().map_or(Some(1), |b| b.parse().ok())?;
+                    Some((ip, until, bans))
+                })();
+                match parsed {
+                    Some((ip, until, bans)) if until > now => {
+                        peers.insert(ip, Peer { banned_until: Some(until), bans, ..Peer::default() });
+                    }
+                    Some(_) => {}
+                    None => log::warn!("Skipping {}:{}: expected `ip until bans`", path.display(), i + 1),
+                }
+            }
+            log::info!("Loaded {} ban(s) from {}", peers.len(), path.display());
+        }
+        Ok(Self { peers: Mutex::new(peers), ban_list: ban_list.map(Path::to_path_buf) })
+    }
+
+    /// Counts a new connection from `ip`, returning why it should be
+    /// dropped if the IP is banned or over its connection rate.
+    pub async fn admit(&self, ip: IpAddr, settings: &RateLimitSettings) -> Result<(), String> {
+        let (now, instant) = (SystemTime::now(), Instant::now());
+        let mut peers = self.peers.lock().await;
+        let peer = track(&mut peers, peer_key(ip, settings), settings, now, instant);
+        if let Some(until) = peer.banned_until.filter(|until| *until > now) {
+            let left = until.duration_since(now).unwrap_or_default();
+            return Err(format!("banned for another {}s", left.as_secs()));
+        }
+        if settings.max_connections_per_minute == 0 {
+            return Ok(());
+        }
+        expire(&mut peer.connections, instant, MINUTE);
+        if peer.connections.len() >= settings.max_connections_per_minute {
+            return Err(format!("more than {} connections per minute", settings.max_connections_per_minute));
+        }
+        peer.connections.push_back(instant);
+        Ok(())
+    }
+
+    /// Counts a connection from `ip` that was rejected and never
+    /// authenticated, banning the IP once it reaches `max_auth_failures`.
+    pub async fn record_failure(&self, ip: IpAddr, settings: &RateLimitSettings) {
+        let (now, instant) = (SystemTime::now(), Instant::now());
+        let key = peer_key(ip, settings);
+        let mut peers = self.peers.lock().await;
+        let peer = track(&mut peers, key, settings, now, instant);
+        expire(&mut peer.failures, instant, Duration::from_secs(settings.find_time));
+        peer.failures.push_back(instant);
+        let failures = peer.failures.len();
+
+        if settings.max_auth_failures > 0 && failures >= settings.max_auth_failures {
+            let ban = Duration::from_secs(settings.ban_time)
+                .saturating_mul(2u32.saturating_pow(peer.bans))
+                .min(Duration::from_secs(settings.max_ban_time));
+            peer.banned_until = Some(now + ban);
+            peer.bans += 1;
+            peer.failures.clear();
+            log::warn!("Banned {} for {}s after {} failed authentications", describe(key, settings), ban.as_secs(), failures);
+            self.save(&peers, now);
+        }
+    }
+
+    /// How long to wait before rejecting an attempt from `ip`: doubled for
+    /// every recent failure.
+    pub async fn backoff(&self, ip: IpAddr, settings: &RateLimitSettings, rejection_time: Duration) -> Duration {
+        if !settings.backoff {
+            return Duration::ZERO;
+        }
+        let instant = Instant::now();
+        let mut peers = self.peers.lock().await;
+        let Some(peer) = peers.get_mut(&peer_key(ip, settings)) else { return Duration::ZERO };
+        expire(&mut peer.failures, instant, Duration::from_secs(settings.find_time));
+        // russh rejects no earlier than `rejection_time` after the request,
+        // time spent here included, so an IP without failures waits no longer.
+        let factor = 2u32.saturating_pow(peer.failures.len() as u32);
+        rejection_time.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+
+    pub async fn record_success(&self, ip: IpAddr, settings: &RateLimitSettings) {
+        if let Some(peer) = self.peers.lock().await.get_mut(&peer_key(ip, settings)) {
+            peer.failures.clear();
+        }
+    }
+
+    fn save(&self, peers: &HashMap<IpAddr, Peer>, now: SystemTime) {
+        let Some(path) = &self.ban_list else { return };
+        let mut list = String::new();
+        for (ip, peer) in peers.iter().filter(|(_, p)| p.banned(now)) {
+            let until = peer.banned_until.and_then(|u| u.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+            list.push_str(&format!("{} {} {}\n", ip, until.as_secs(), peer.bans));
+        }
+        if let Err(e) = write_atomic(path, list.as_bytes(), 0o600) {
+            log::warn!("Failed to save ban list: {}", e);
+        }
+    }
+}
+
+/// The address `ip` is tracked under.
+fn peer_key(ip: IpAddr, settings: &RateLimitSettings) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(settings.ipv6_prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+        ip => ip,
+    }
+}
+
+/// `key` for log lines, with its prefix if it stands for an IPv6 network.
+fn describe(key: IpAddr, settings: &RateLimitSettings) -> String {
+    match key {
+        IpAddr::V6(_) if settings.ipv6_prefix < 128 => format!("{}/{}", key, settings.ipv6_prefix),
+        _ => key.to_string(),
+    }
+}
+
+/// The entry for `key`, making room for it if the table is full.
+fn track<'a>(
+    peers: &'a mut HashMap<IpAddr, Peer>,
+    key: IpAddr,
+    settings: &RateLimitSettings,
+    now: SystemTime,
+    instant: Instant,
+) -> &'a mut Peer {
+    if !peers.contains_key(&key) && peers.len() >= MAX_TRACKED {
+        prune(peers, settings, now, instant);
+        evict(peers, now);
+    }
+    let peer = peers.entry(key).or_default();
+    peer.last_seen = instant;
+    peer
+}
+
+fn expire(times: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while times.front().is_some_and(|t| now.duration_since(*t) >= window) {
+        times.pop_front();
+    }
+}
+
+fn prune(peers: &mut HashMap<IpAddr, Peer>, settings: &RateLimitSettings, now: SystemTime, instant: Instant) {
+    let find_time = Duration::from_secs(settings.find_time);
+    peers.retain(|_, peer| {
+        expire(&mut peer.connections, instant, MINUTE);
+        expire(&mut peer.failures, instant, find_time);
+        !peer.idle(now)
+    });
+}
+
+/// Drops the least recently seen peers, banned ones last, down to
+/// `EVICT_TO`.
+fn evict(peers: &mut HashMap<IpAddr, Peer>, now: SystemTime) {
+    if peers.len() < MAX_TRACKED {
+        return;
+    }
+    let mut by_age: Vec<_> = peers.iter().map(|(ip, peer)| (peer.banned(now), peer.last_seen, *ip)).collect();
+    by_age.sort_unstable();
+    let excess = peers.len() - EVICT_TO;
+    for (_, _, ip) in &by_age[..excess] {
+        peers.remove(ip);
+    }
+    log::warn!("Tracking too many peers, dropped the {} least recently seen", excess);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> RateLimitSettings {
+        RateLimitSettings { max_auth_failures: 3, ban_time: 100, max_ban_time: 250, ..RateLimitSettings::default() }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    async fn ban_left(limiter: &Limiter, ip: IpAddr, settings: &RateLimitSettings) -> Option<u64> {
+        let peers = limiter.peers.lock().await;
+        let until = peers.get(&peer_key(ip, settings))?.banned_until?;
+        Some(until.duration_since(SystemTime::now()).ok()?.as_secs())
+    }
+
+    async fn lift_ban(limiter: &Limiter, ip: IpAddr, settings: &RateLimitSettings) {
+        limiter.peers.lock().await.get_mut(&peer_key(ip, settings)).unwrap().banned_until = Some(UNIX_EPOCH);
+    }
+
+    #[tokio::test]
+    async fn failures_ban_and_each_ban_doubles() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), settings());
+        let peer = ip("192.0.2.1");
+        for expected in [100, 200, 250] {
+            limiter.admit(peer, &settings).await.unwrap();
+            for _ in 0..settings.max_auth_failures {
+                limiter.record_failure(peer, &settings).await;
+            }
+            assert!(limiter.admit(peer, &settings).await.unwrap_err().starts_with("banned for another"));
+            assert!(ban_left(&limiter, peer, &settings).await.is_some_and(|left| (expected - 2..=expected).contains(&left)));
+            lift_ban(&limiter, peer, &settings).await;
+        }
+        assert!(limiter.admit(ip("192.0.2.2"), &settings).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn success_clears_failures() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), settings());
+        let peer = ip("192.0.2.1");
+        limiter.record_failure(peer, &settings).await;
+        limiter.record_failure(peer, &settings).await;
+        limiter.record_success(peer, &settings).await;
+        limiter.record_failure(peer, &settings).await;
+        assert!(limiter.admit(peer, &settings).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_per_failure() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), RateLimitSettings { max_auth_failures: 0, ..settings() });
+        let (peer, base) = (ip("192.0.2.1"), Duration::from_millis(100));
+        assert_eq!(limiter.backoff(peer, &settings, base).await, Duration::ZERO);
+        limiter.admit(peer, &settings).await.unwrap();
+        assert_eq!(limiter.backoff(peer, &settings, base).await, base);
+        limiter.record_failure(peer, &settings).await;
+        limiter.record_failure(peer, &settings).await;
+        assert_eq!(limiter.backoff(peer, &settings, base).await, base * 4);
+        for _ in 0..10 {
+            limiter.record_failure(peer, &settings).await;
+        }
+        assert_eq!(limiter.backoff(peer, &settings, base).await, MAX_BACKOFF);
+        let off = RateLimitSettings { backoff: false, ..settings.clone() };
+        assert_eq!(limiter.backoff(peer, &off, base).await, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn connection_rate() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), RateLimitSettings { max_connections_per_minute: 2, ..settings() });
+        let peer = ip("192.0.2.1");
+        assert!(limiter.admit(peer, &settings).await.is_ok());
+        assert!(limiter.admit(ip("::ffff:192.0.2.1"), &settings).await.is_ok());
+        assert!(limiter.admit(peer, &settings).await.is_err());
+        assert!(limiter.admit(ip("192.0.2.2"), &settings).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ipv6_peers_are_grouped_by_prefix() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), settings());
+        for i in 1..=3 {
+            limiter.record_failure(ip(&format!("2001:db8:0:1::{i}")), &settings).await;
+        }
+        assert!(limiter.admit(ip("2001:db8:0:1:ffff::1"), &settings).await.is_err());
+        assert!(limiter.admit(ip("2001:db8:0:2::1"), &settings).await.is_ok());
+        assert_eq!(describe(peer_key(ip("2001:db8:0:1::9"), &settings), &settings), "2001:db8:0:1::/64");
+
+        let single = RateLimitSettings { ipv6_prefix: 128, ..settings.clone() };
+        for i in 1..=3 {
+            limiter.record_failure(ip(&format!("2001:db8:0:3::{i}")), &single).await;
+        }
+        assert!(limiter.admit(ip("2001:db8:0:3::4"), &single).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_table_drops_the_least_recently_seen() {
+        let (limiter, settings) = (Limiter::open(None).unwrap(), settings());
+        let (oldest, banned) = (ip("10.255.0.1"), ip("10.255.0.2"));
+        limiter.admit(banned, &settings).await.unwrap();
+        for _ in 0..settings.max_auth_failures {
+            limiter.record_failure(banned, &settings).await;
+        }
+        limiter.admit(oldest, &settings).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        for i in 0..MAX_TRACKED as u32 {
+            limiter.admit(IpAddr::V4(i.into()), &settings).await.unwrap();
+        }
+
+        let peers = limiter.peers.lock().await;
+        assert!(peers.len() <= MAX_TRACKED);
+        assert!(!peers.contains_key(&oldest));
+        assert!(peers.contains_key(&banned));
+        assert!(peers.contains_key(&IpAddr::V4((MAX_TRACKED as u32 - 1).into())));
+    }
+}
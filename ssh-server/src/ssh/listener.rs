@@ -0,0 +1,83 @@
+use std::{env, io, net, os::fd::{FromRawFd, RawFd}};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use crate::config::ListenerSettings;
+
+const BACKLOG: i32 = 1024;
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug)]
+pub struct Listener {
+    pub socket: TcpListener,
+    pub settings: ListenerSettings,
+}
+
+impl Listener {
+    pub fn bind(settings: ListenerSettings) -> io::Result<Self> {
+        let addr = settings.address;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        socket.set_reuse_address(true)?;
+        if let (true, Some(v6_only)) = (addr.is_ipv6(), settings.v6_only) {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(BACKLOG)?;
+
+        let socket = TcpListener::from_std(socket.into())?;
+        Ok(Self { socket, settings })
+    }
+
+    /// Uses the sockets passed by systemd when the process was socket
+    /// activated, otherwise binds `settings`.
+    pub fn open_all(settings: Vec<ListenerSettings>) -> Result<Vec<Self>, String> {
+        match Self::from_systemd(&settings)? {
+            Some(listeners) => Ok(listeners),
+            None => Self::bind_all(settings),
+        }
+    }
+
+    /// Takes over the sockets announced by `LISTEN_PID`/`LISTEN_FDS`. A socket
+    /// whose address matches a configured listener keeps that listener's
+    /// route restrictions.
+    pub fn from_systemd(configured: &[ListenerSettings]) -> Result<Option<Vec<Self>>, String> {
+        let Ok(pid) = env::var("LISTEN_PID") else { return Ok(None) };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(None);
+        }
+        let count: RawFd = env::var("LISTEN_FDS").ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or("LISTEN_FDS is missing or invalid")?;
+
+        let listeners = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                // SAFETY: systemd hands this process ownership of the fds
+                // from SD_LISTEN_FDS_START up to LISTEN_FDS, and nothing else
+                // has touched them.
+                let std_listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+                let activated = |e: io::Error| format!("Activated socket fd {}: {}", fd, e);
+                std_listener.set_nonblocking(true).map_err(activated)?;
+                let address = std_listener.local_addr().map_err(activated)?;
+                let settings = configured.iter()
+                    .find(|l| l.address == address)
+                    .cloned()
+                    .unwrap_or_else(|| ListenerSettings::new(address));
+                let socket = TcpListener::from_std(std_listener).map_err(activated)?;
+                log::info!("Using socket-activated listener on {}", address);
+                Ok(Self { socket, settings })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Some(listeners))
+    }
+
+    pub fn bind_all(settings: Vec<ListenerSettings>) -> Result<Vec<Self>, String> {
+        settings.into_iter()
+            .map(|s| {
+                let addr = s.address;
+                Self::bind(s).map_err(|e| format!("Failed to bind {}: {}", addr, e))
+            })
+            .collect()
+    }
+}
@@ -1,5 +1,15 @@
 pub mod server;
 pub mod auth;
+pub mod authorized_keys;
 pub mod app;
+pub mod actor;
+pub mod channel;
+pub mod commands;
 pub mod terminal;
 pub mod keypair;
+pub mod listener;
+pub mod limiter;
+pub mod user_ca;
+pub mod totp;
+pub mod policy;
+pub mod session;
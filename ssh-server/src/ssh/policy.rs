@@ -0,0 +1,291 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use russh::keys::{Certificate, PublicKey};
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::config::{Settings, SettingsRx};
+use crate::ssh::{auth::KeyRecord, authorized_keys::{AuthorizedKeys, KeyOptions}, user_ca::UserCa};
+
+pub type PolicyFuture<'a> = Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+
+/// One authentication attempt whose signature has already been verified.
+#[derive(Debug)]
+pub struct AuthRequest<'a> {
+    pub username: &'a str,
+    /// The signing key; for a certificate, the certified key
+    pub key: &'a PublicKey,
+    pub certificate: Option<&'a Certificate>,
+    pub peer: Option<SocketAddr>,
+    /// Earlier attempts with this key, from the auth log
+    pub history: Option<&'a KeyRecord>,
+}
+
+/// Where an accepted client goes and what it may do there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub route: String,
+    pub key_options: Option<KeyOptions>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Accept(Grant),
+    /// The key is good, but a TOTP code is needed before the session starts
+    Partial(Grant),
+    /// Refused, with the reason to log
+    Reject(String),
+}
+
+/// Decides who gets in. The server applies `[key_policy]` and the listener
+/// routes around it, and runs the TOTP step for `Decision::Partial`. A
+/// policy carries its own configuration; plug one in with
+/// `SshServer::with_policy`.
+pub trait AuthPolicy: Send + Sync + Debug {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a>;
+}
+
+/// The built-in policies, picked with `auth_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthPolicyKind {
+    #[default]
+    Routes,
+    Open,
+    Allowlist,
+    Certificate,
+}
+
+impl AuthPolicyKind {
+    /// The policy, configured from `settings`.
+    pub fn build(self, settings: &Settings) -> Arc<dyn AuthPolicy> {
+        match self {
+            Self::Routes => Arc::new(RoutesPolicy::new(settings)),
+            Self::Open => Arc::new(OpenPolicy),
+            Self::Allowlist => Arc::new(AllowlistPolicy::new(settings.authorized_keys.clone())),
+            Self::Certificate => Arc::new(CertificatePolicy::new(settings.user_ca.clone())),
+        }
+    }
+}
+
+/// The built-in policy picked by `auth_policy`, built from the current
+/// settings for each attempt so reloads reach it.
+#[derive(Debug)]
+pub struct ConfiguredPolicy {
+    settings_rx: SettingsRx,
+}
+
+impl ConfiguredPolicy {
+    pub fn new(settings_rx: SettingsRx) -> Self {
+        Self { settings_rx }
+    }
+}
+
+impl AuthPolicy for ConfiguredPolicy {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a> {
+        let settings = self.settings_rx.borrow().clone();
+        let policy = settings.auth_policy.build(&settings);
+        Box::pin(async move { policy.check(request).await })
+    }
+}
+
+/// Any key, on the route named by the username.
+#[derive(Debug, Clone, Copy)]
+pub struct OpenPolicy;
+
+impl OpenPolicy {
+    fn decide(&self, request: &AuthRequest) -> Decision {
+        Decision::Accept(Grant { route: request.username.to_string(), key_options: None })
+    }
+}
+
+impl AuthPolicy for OpenPolicy {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a> {
+        Box::pin(async move { self.decide(request) })
+    }
+}
+
+/// Only keys in `authorized_keys_files`, under their options.
+#[derive(Debug, Clone)]
+pub struct AllowlistPolicy {
+    authorized_keys: Arc<AuthorizedKeys>,
+}
+
+impl AllowlistPolicy {
+    pub fn new(authorized_keys: Arc<AuthorizedKeys>) -> Self {
+        Self { authorized_keys }
+    }
+
+    /// None when the key is not listed.
+    fn lookup(&self, request: &AuthRequest) -> Option<Decision> {
+        let entry = self.authorized_keys.find(request.key)?;
+        if let Err(reason) = entry.options.permits(request.peer.map(|p| p.ip())) {
+            return Some(Decision::Reject(reason));
+        }
+        let route = entry.options.command.as_deref().unwrap_or(request.username);
+        Some(Decision::Accept(Grant { route: route.to_string(), key_options: Some(entry.options.clone()) }))
+    }
+}
+
+impl AuthPolicy for AllowlistPolicy {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a> {
+        Box::pin(async move {
+            self.lookup(request).unwrap_or_else(|| Decision::Reject("key not listed".to_string()))
+        })
+    }
+}
+
+/// Only user certificates from `trusted_user_ca_keys` that name the
+/// username as a principal, as sshd requires. A `force-command` then picks
+/// the route.
+#[derive(Debug, Clone)]
+pub struct CertificatePolicy {
+    user_ca: Arc<UserCa>,
+}
+
+impl CertificatePolicy {
+    pub fn new(user_ca: Arc<UserCa>) -> Self {
+        Self { user_ca }
+    }
+
+    fn decide(&self, request: &AuthRequest) -> Decision {
+        let Some(certificate) = request.certificate else {
+            return Decision::Reject("not a certificate".to_string());
+        };
+        let options = match self.user_ca.check(certificate)
+            .and_then(|o| o.permits(request.peer.map(|p| p.ip())).map(|()| o))
+        {
+            Ok(options) => options,
+            Err(reason) => return Decision::Reject(format!("certificate {:?}: {reason}", certificate.key_id())),
+        };
+        if !certificate.valid_principals().iter().any(|p| p == request.username) {
+            return Decision::Reject(format!(
+                "{} is not a principal of certificate {:?}", request.username, certificate.key_id(),
+            ));
+        }
+        let route = options.command.as_deref().unwrap_or(request.username).to_string();
+        Decision::Accept(Grant { route, key_options: Some(options) })
+    }
+}
+
+impl AuthPolicy for CertificatePolicy {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a> {
+        Box::pin(async move { self.decide(request) })
+    }
+}
+
+/// The default, driven by the route settings: certificates go through
+/// `CertificatePolicy`, listed keys through `AllowlistPolicy` (their options
+/// apply on every route) and other keys only reach routes that are not
+/// private. `totp_routes` turn an accept into a partial success.
+#[derive(Debug, Clone)]
+pub struct RoutesPolicy {
+    allowlist: AllowlistPolicy,
+    certificates: CertificatePolicy,
+    private_routes: Vec<String>,
+    totp_routes: Vec<String>,
+}
+
+impl RoutesPolicy {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            allowlist: AllowlistPolicy::new(settings.authorized_keys.clone()),
+            certificates: CertificatePolicy::new(settings.user_ca.clone()),
+            private_routes: settings.private_routes.clone(),
+            totp_routes: settings.totp_routes.clone(),
+        }
+    }
+
+    fn decide(&self, request: &AuthRequest) -> Decision {
+        let decision = if request.certificate.is_some() {
+            self.certificates.decide(request)
+        } else {
+            self.allowlist.lookup(request).unwrap_or_else(|| {
+                if self.private_routes.iter().any(|r| r == request.username) {
+                    Decision::Reject(format!("key not authorized for {}", request.username))
+                } else {
+                    OpenPolicy.decide(request)
+                }
+            })
+        };
+        match decision {
+            Decision::Accept(grant) if self.totp_routes.contains(&grant.route) => Decision::Partial(grant),
+            decision => decision,
+        }
+    }
+}
+
+impl AuthPolicy for RoutesPolicy {
+    fn check<'a>(&'a self, request: &'a AuthRequest<'a>) -> PolicyFuture<'a> {
+        Box::pin(async move { self.decide(request) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn cert(name: &str) -> Certificate {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/krl/{name}-cert.pub"));
+        Certificate::from_openssh(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// The key certified by a fixture certificate, used on its own
+    fn key(name: &str) -> PublicKey {
+        PublicKey::new(cert(name).public_key().clone(), name)
+    }
+
+    fn policy() -> RoutesPolicy {
+        let path = std::env::temp_dir().join(format!("ssh-server-policy-{}", std::process::id()));
+        let lines = [
+            format!("command=\"admin\" {}", key("forced").to_openssh().unwrap()),
+            format!("from=\"10.0.0.0/8\" {}", key("nopty").to_openssh().unwrap()),
+            key("serial7").to_openssh().unwrap(),
+        ];
+        fs::write(&path, lines.join("\n")).unwrap();
+        let authorized_keys = AuthorizedKeys::load(std::slice::from_ref(&path));
+        fs::remove_file(&path).unwrap();
+        let ca_keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/krl/ca_keys");
+        RoutesPolicy {
+            allowlist: AllowlistPolicy::new(Arc::new(authorized_keys.unwrap())),
+            certificates: CertificatePolicy::new(Arc::new(UserCa::load(Some(&ca_keys), None).unwrap())),
+            private_routes: vec!["admin".to_string(), "ops".to_string()],
+            totp_routes: vec!["ops".to_string()],
+        }
+    }
+
+    #[test]
+    fn routes_policy_decisions() {
+        let policy = policy();
+        for (username, name, certified, peer, expected) in [
+            ("admin", "mallory", false, "192.0.2.1", "reject: key not authorized for admin"),
+            ("tui", "mallory", false, "192.0.2.1", "accept tui"),
+            ("tui", "forced", false, "192.0.2.1", "accept admin"),
+            ("tui", "nopty", false, "192.0.2.1", "reject: 192.0.2.1 does not match from=\"10.0.0.0/8\""),
+            ("admin", "nopty", false, "10.1.2.3", "accept admin"),
+            ("bob", "alice", true, "192.0.2.1", "reject: bob is not a principal of certificate \"alice\""),
+            ("admin", "alice", true, "192.0.2.1", "accept admin"),
+            ("ops", "serial7", false, "192.0.2.1", "partial ops"),
+            ("ops", "mallory", false, "192.0.2.1", "reject: key not authorized for ops"),
+        ] {
+            let certificate = cert(name);
+            let request = AuthRequest {
+                username,
+                key: &key(name),
+                certificate: certified.then_some(&certificate),
+                peer: Some(format!("{peer}:50000").parse().unwrap()),
+                history: None,
+            };
+            let decision = match policy.decide(&request) {
+                Decision::Accept(grant) => format!("accept {}", grant.route),
+                Decision::Partial(grant) => format!("partial {}", grant.route),
+                Decision::Reject(reason) => format!("reject: {reason}"),
+            };
+            assert_eq!(decision, expected, "{username} with {name}");
+        }
+    }
+}
@@ -1,202 +1,664 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Instant};
 use russh::{
-    Channel, ChannelId, CryptoVec, Pty,
-    keys::PublicKey,
-    server::{self, Auth, Config, Handle, Msg, Session, Server as _},
-    Error as SshError,
+    Channel, ChannelId, Pty,
+    keys::{Certificate, HashAlg, PublicKey},
+    server::{self, Auth, ChannelOpenHandle, Config, Msg, Response, Session, Server as _},
+    Error as SshError, MethodKind, MethodSet,
 };
 
-use crate::ssh::{auth::AuthLog, terminal::TerminalHandle};
+use crate::config::{ListenerSettings, Settings, SettingsRx};
+use crate::ssh::{auth::AuthLog, authorized_keys::KeyOptions, limiter::Limiter, listener::Listener, totp::TotpStore};
+use crate::ssh::policy::{AuthPolicy, AuthRequest, Decision};
+use crate::ssh::session::{ChannelDetails, SessionContext, SharedContext, TotpEnrolment, MAX_ENV_VARS};
+use crate::ssh::terminal::TerminalHandle;
+use crate::ssh::actor::{close_connection, Clients, SessionActor, SessionHandle, SessionMsg};
+use crate::ssh::channel::{ChannelEvent, ChannelState, SessionChannel};
 use crate::ssh::app::App;
 
+const GUEST_NOTICE: &str = "No SSH key was accepted, continuing as a guest.";
+const TOTP_PROMPT: &str = "Verification code: ";
+/// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A key accepted for a TOTP route, waiting for its code.
+#[derive(Clone, Debug)]
+pub struct SecondFactor {
+    username: String,
+    route: String,
+    key: PublicKey,
+    key_options: Option<KeyOptions>,
+}
+
 #[derive(Clone, Debug)]
 pub struct SshServer {
-    pub clients: Arc<Mutex<HashMap<usize, (ChannelId, Handle, App)>>>,
-    pub id: usize,
+    pub clients: Clients,
+    /// This connection's session channels, so input goes straight to their
+    /// actors without touching `clients`
+    channels: HashMap<ChannelId, SessionChannel>,
+    next_id: Arc<AtomicUsize>,
     pub auth_log: Arc<AuthLog>,
-    pub protocol: Option<String>
+    pub limiter: Arc<Limiter>,
+    pub totp: Arc<TotpStore>,
+    /// Decides which keys and certificates get in; every key is refused
+    /// until `with_policy` sets one
+    policy: Option<Arc<dyn AuthPolicy>>,
+    /// Listener the connection came in on
+    pub listener: Option<Arc<ListenerSettings>>,
+    /// What is known about this connection, shared with its app
+    pub context: SharedContext,
+    /// Set between an accepted key and its TOTP code on a `totp_routes` route
+    pub second_factor: Option<SecondFactor>,
+    /// Snapshot taken when the connection was accepted
+    pub settings: Arc<Settings>,
+    settings_rx: SettingsRx,
 }
 
 impl SshServer {
-    pub fn new() -> Self {
+    pub fn new(settings_rx: SettingsRx, auth_log: AuthLog, limiter: Limiter, totp: TotpStore) -> Self {
+        let settings = settings_rx.borrow().clone();
         Self {
             clients: Arc::new(Mutex::new(HashMap::new())),
-            id: 0,
-            auth_log: Arc::new(AuthLog::new()),
-            protocol: None
+            channels: HashMap::new(),
+            next_id: Arc::new(AtomicUsize::new(1)),
+            auth_log: Arc::new(auth_log),
+            limiter: Arc::new(limiter),
+            totp: Arc::new(totp),
+            policy: None,
+            listener: None,
+            context: SharedContext::new(SessionContext::new(0, None)),
+            second_factor: None,
+            settings,
+            settings_rx,
         }
     }
 
+    /// Sets the auth policy: `ConfiguredPolicy` for the built-in ones, or
+    /// another implementation to plug in other membership checks.
+    pub fn with_policy(mut self, policy: Arc<dyn AuthPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Serves every listener from clones of this server, so sessions share
+    /// the clients map and auth log whichever address they came in on.
+    /// Stops accepting connections once `shutdown` resolves; sessions
+    /// already running are left to `shutdown`.
     pub async fn run(
-        mut self,
+        &self,
         config: Arc<Config>,
-        addr: (&str, u16)
+        listeners: Vec<Listener>,
+        shutdown: impl Future<Output = ()>,
     ) -> Result<(), SshError> {
-        self.run_on_address(config, addr).await?;
-        Ok(())
+        let mut tasks = JoinSet::new();
+        for Listener { socket, settings } in listeners {
+            let mut server = self.clone();
+            server.listener = Some(Arc::new(settings));
+            let config = config.clone();
+            tasks.spawn(server.accept(config, socket));
+        }
+
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                result = tasks.join_next() => match result {
+                    Some(result) => result.map_err(io::Error::other)??,
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Accepts connections on one listener. Peers that are banned or over
+    /// their connection rate are dropped before the SSH handshake. A
+    /// connection that was rejected and never authenticated counts as one
+    /// failure for its IP, however many keys it offered.
+    async fn accept(mut self, config: Arc<Config>, socket: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = match socket.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept a connection: {}", e);
+                    sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let settings = self.settings_rx.borrow().clone();
+            if let Err(reason) = self.limiter.admit(peer.ip(), &settings.rate_limit).await {
+                log::info!("Connection refused | ip: {}, reason: {}", peer.ip(), reason);
+                continue;
+            }
+
+            let handler = self.new_client(Some(peer));
+            let context = handler.context.clone();
+            let id = context.read().id;
+            let clients = self.clients.clone();
+            let limiter = self.limiter.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                if config.nodelay && let Err(e) = stream.set_nodelay(true) {
+                    log::warn!("set_nodelay() failed: {}", e);
+                }
+                let result = match server::run_stream(config, stream, handler).await {
+                    Ok(session) => session.await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    log::warn!("Session error from {}: {}", peer, e);
+                }
+                let failed = {
+                    let ctx = context.read();
+                    ctx.authenticated_at.is_none() && ctx.auth_failures > 0
+                };
+                if failed {
+                    limiter.record_failure(peer.ip(), &settings.rate_limit).await;
+                }
+                close_connection(&clients, id).await;
+            });
+        }
+    }
+
+    /// Asks the auth policy about a key (or the key of a certificate) that
+    /// passed `[key_policy]`, then checks the route against the listener.
+    async fn authorize(&mut self, username: &str, key: &PublicKey, certificate: Option<&Certificate>) -> Result<Auth, SshError> {
+        let history = self.auth_log.history(key).await;
+        let request = AuthRequest {
+            username,
+            key,
+            certificate,
+            peer: self.context.read().peer,
+            history: history.as_ref(),
+        };
+        let decision = match &self.policy {
+            Some(policy) => policy.check(&request).await,
+            None => Decision::Reject("no auth policy is set".to_string()),
+        };
+        let attempts = request.history.map_or(0, |h| h.attempts);
+        let (grant, partial) = match decision {
+            Decision::Accept(grant) => (grant, false),
+            Decision::Partial(grant) => (grant, true),
+            Decision::Reject(reason) => {
+                log::info!("Authentication rejected | user: {username}, reason: {reason}");
+                return self.reject().await;
+            }
+        };
+        let route = grant.route.as_str();
+        if !self.route_allowed(route) {
+            log::info!("Authentication rejected | unknown route: {route}");
+            return self.reject().await;
+        }
+
+        if let Some(certificate) = certificate {
+            log::info!(
+                "Certificate accepted | key_id: {:?}, serial: {}, ca: {}",
+                certificate.key_id(),
+                certificate.serial(),
+                certificate.signature_key().fingerprint(HashAlg::Sha256),
+            );
+        }
+        log::info!(
+            "Authentication attempt | user: {username}, route: {route}, key_type: {}, comment: \"{}\", seen before: {attempts}",
+            key.algorithm(),
+            key.comment(),
+        );
+        if partial {
+            return self.require_totp(username, route, key, grant.key_options).await;
+        }
+        self.grant(username, route, key, grant.key_options).await
+    }
+
+    async fn grant(&mut self, username: &str, route: &str, key: &PublicKey, key_options: Option<KeyOptions>) -> Result<Auth, SshError> {
+        self.context.update(|ctx| ctx.route = Some(route.to_string()));
+        self.accept_key(username, key, key_options).await
+    }
+
+    async fn accept_key(&mut self, username: &str, key: &PublicKey, key_options: Option<KeyOptions>) -> Result<Auth, SshError> {
+        let ip = self.context.read().peer.map(|p| p.ip());
+        if let Some(ip) = ip {
+            self.limiter.record_success(ip, &self.settings.rate_limit).await;
+        }
+        self.context.update(|ctx| {
+            ctx.username = Some(username.to_string());
+            ctx.key_fingerprint = Some(key.fingerprint(HashAlg::Sha256).to_string());
+            ctx.key_algorithm = Some(key.algorithm().to_string());
+            ctx.key_options = key_options;
+        });
+        self.auth_log.record_key(username, key, ip).await;
+
+        Ok(Auth::Accept)
+    }
+
+    /// Asks an enrolled key for its TOTP code through keyboard-interactive.
+    /// A key that is not enrolled yet gets a setup session instead.
+    async fn require_totp(&mut self, username: &str, route: &str, key: &PublicKey, key_options: Option<KeyOptions>) -> Result<Auth, SshError> {
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        if !self.totp.is_enrolled(&fingerprint).await {
+            return self.start_totp_setup(username, route, key, key_options).await;
+        }
+        self.second_factor = Some(SecondFactor {
+            username: username.to_string(),
+            route: route.to_string(),
+            key: key.clone(),
+            key_options,
+        });
+        // russh clears partial_success, but the client still moves on to the
+        // one method left.
+        Ok(Auth::Reject {
+            proceed_with_methods: Some(MethodSet::from(&[MethodKind::KeyboardInteractive][..])),
+            partial_success: true,
+        })
+    }
+
+    /// Lets a key that is not enrolled in without its route: the TUI shows
+    /// it a new secret and enrols it with the first code, and the next login
+    /// asks for a code as usual. Only keys the allowlist or a trusted CA
+    /// vouched for (their grant carries key options) get this far; anyone
+    /// could enrol any other key.
+    async fn start_totp_setup(&mut self, username: &str, route: &str, key: &PublicKey, key_options: Option<KeyOptions>) -> Result<Auth, SshError> {
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        if key_options.is_none() {
+            log::info!("TOTP enrolment refused | user: {username}, fingerprint: {fingerprint}, reason: key is not listed or certified");
+            return self.reject().await;
+        }
+        let account = format!("{route}-{}", &fingerprint["SHA256:".len()..][..8]);
+        let otpauth_uri = match self.totp.start_enrolment(&fingerprint, &account).await {
+            Ok(uri) => uri,
+            Err(reason) => {
+                log::warn!("TOTP enrolment failed | user: {username}, fingerprint: {fingerprint}, reason: {reason}");
+                return self.reject().await;
+            }
+        };
+        log::info!("TOTP enrolment started | user: {username}, route: {route}, fingerprint: {fingerprint}");
+        let enrolment = TotpEnrolment { route: route.to_string(), otpauth_uri };
+        self.context.update(|ctx| ctx.totp_enrolment = Some(enrolment));
+        self.accept_key(username, key, key_options).await
+    }
+
+    async fn verify_totp(&mut self, username: &str, pending: SecondFactor, response: Option<Response<'_>>) -> Result<Auth, SshError> {
+        if username != pending.username {
+            log::info!("TOTP rejected | user changed from {} to {username}", pending.username);
+            return self.reject().await;
+        }
+        let Some(mut response) = response else {
+            let instructions = format!("The {} route needs a code from your authenticator app.", pending.route);
+            return Ok(Auth::Partial {
+                name: "Two-factor authentication".into(),
+                instructions: instructions.into(),
+                prompts: Cow::Owned(vec![(TOTP_PROMPT.into(), false)]),
+            });
+        };
+        let code = response.next().map(|c| String::from_utf8_lossy(&c).trim().to_string()).unwrap_or_default();
+        let fingerprint = pending.key.fingerprint(HashAlg::Sha256).to_string();
+        if let Err(reason) = self.totp.verify(&fingerprint, &code).await {
+            log::info!("TOTP rejected | user: {username}, fingerprint: {fingerprint}, reason: {reason}");
+            return self.reject().await;
+        }
+
+        log::info!("TOTP accepted | user: {username}, route: {}", pending.route);
+        self.second_factor = None;
+        self.grant(username, &pending.route, &pending.key, pending.key_options).await
+    }
+
+    /// Rejects an authentication attempt, waiting out any backoff. The
+    /// connection's failure is counted against its IP when it closes.
+    async fn reject(&self) -> Result<Auth, SshError> {
+        let ip = self.context.read().peer.map(|p| p.ip());
+        self.context.update(|ctx| ctx.auth_failures += 1);
+        if let Some(ip) = ip {
+            let rejection_time = Duration::from_secs(self.settings.ssh.auth_rejection_time);
+            let delay = self.limiter.backoff(ip, &self.settings.rate_limit, rejection_time).await;
+            sleep(delay).await;
+        }
+        Ok(Auth::reject())
+    }
+
+    /// Has every live session send a farewell frame and close its channel,
+    /// then waits up to `grace` for the connections to go away.
+    pub async fn shutdown(&self, grace: Duration) {
+        let sessions: Vec<SessionHandle> = self.clients.lock().await
+            .iter()
+            .map(|((_, channel), session)| {
+                log::debug!("Draining {} channel {channel}", session.context.read());
+                session.clone()
+            })
+            .collect();
+        log::info!("Draining {} session(s)", sessions.len());
+
+        let deadline = Instant::now() + grace;
+        for session in sessions {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let _ = timeout(left, session.send(SessionMsg::Shutdown)).await;
+        }
+
+        while Instant::now() < deadline && !self.clients.lock().await.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        self.auth_log.flush().await;
+        let stats = self.auth_log.stats().await;
+        log::info!(
+            "Auth log: {} key(s), {} evicted over capacity, {} expired",
+            stats.keys, stats.evicted_capacity, stats.evicted_expired,
+        );
+    }
+
+    fn channel_details(&self, channel: ChannelId) -> ChannelDetails {
+        self.channels.get(&channel).map(|c| c.details.clone()).unwrap_or_default()
+    }
+
+    fn client_key(&self, channel: ChannelId) -> (usize, ChannelId) {
+        (self.context.read().id, channel)
+    }
+
+    /// Moves the channel's state machine on `event`, returning its actor's
+    /// mailbox. Unknown channels and out-of-order requests are refused.
+    fn advance(&mut self, channel: ChannelId, event: ChannelEvent) -> Option<SessionHandle> {
+        let Some(entry) = self.channels.get_mut(&channel) else {
+            log::info!("{event:?} request on unknown channel {channel} | {}", self.context.read());
+            return None;
+        };
+        match entry.state.next(event) {
+            Ok(state) => {
+                entry.state = state;
+                Some(entry.session.clone())
+            }
+            Err(reason) => {
+                log::info!("Refused {reason} on channel {channel} | {}", self.context.read());
+                None
+            }
+        }
+    }
+
+    fn route_allowed(&self, route: &str) -> bool {
+        self.settings.route_allowed(route)
+            && self.listener.as_ref().is_none_or(|l| l.route_allowed(route))
     }
 }
 
 impl server::Server for SshServer {
     type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
-        self.id += 1;
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> Self {
+        let mut s = self.clone();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        s.context = SharedContext::new(SessionContext::new(id, peer));
+        s.settings = self.settings_rx.borrow().clone();
         s
     }
-    fn handle_session_error(&mut self, _error: <Self::Handler as server::Handler>::Error) {
-        eprintln!("Session error: {:#?}", _error);
-    }
 }
 
 impl server::Handler for SshServer {
     type Error = russh::Error;
 
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        Ok(self.settings.banner.clone())
+    }
+
     async fn auth_publickey(
         &mut self,
         username: &str,
         key: &PublicKey,
     ) -> Result<Auth, Self::Error> {
-        let allowed_key_type = self.auth_log.eval_key(key).await;
-        let comment = key.comment();
+        if let Err(reason) = self.auth_log.eval_key(key, &self.settings.key_policy).await {
+            log::info!("Authentication rejected | user: {username}, key_type: {}, reason: {reason}", key.algorithm());
+            return self.reject().await;
+        }
 
-        log::info!(
-        "Authentication attempt | user: {username}, key_type: {allowed_key_type}, comment: \"{comment}\""
-    );
+        self.authorize(username, key, None).await
+    }
 
-        self.protocol = Some(username.to_string());
-        self.auth_log.record_key(username, key).await;
+    async fn auth_openssh_certificate(
+        &mut self,
+        username: &str,
+        certificate: &Certificate,
+    ) -> Result<Auth, Self::Error> {
+        let key = PublicKey::from(certificate.public_key().clone());
+        if let Err(reason) = self.auth_log.eval_key(&key, &self.settings.key_policy).await {
+            log::info!("Certificate rejected | user: {username}, key_type: {}, reason: {reason}", key.algorithm());
+            return self.reject().await;
+        }
+        self.authorize(username, &key, Some(certificate)).await
+    }
 
+    /// Asks for the TOTP code after a key accepted for a `totp_routes`
+    /// route. Otherwise lets clients without a usable key into
+    /// `guest_route`: the only round trip shows a notice with no prompts, so
+    /// nothing is asked or checked.
+    async fn auth_keyboard_interactive<'a>(
+        &'a mut self,
+        username: &str,
+        _submethods: &str,
+        response: Option<Response<'a>>,
+    ) -> Result<Auth, Self::Error> {
+        if let Some(pending) = self.second_factor.clone() {
+            return self.verify_totp(username, pending, response).await;
+        }
+        let Some(route) = self.settings.guest_route.clone() else {
+            return self.reject().await;
+        };
+        if !self.route_allowed(&route) {
+            log::info!("Guest login rejected | user: {username}, route {route} is not served here");
+            return self.reject().await;
+        }
+        if response.is_none() {
+            return Ok(Auth::Partial {
+                name: "Guest access".into(),
+                instructions: GUEST_NOTICE.into(),
+                prompts: Cow::Borrowed(&[]),
+            });
+        }
+
+        log::info!("Guest login | user: {username}, route: {route}, peer: {:?}", self.context.read().peer);
+        self.context.update(|ctx| {
+            ctx.username = Some(username.to_string());
+            ctx.route = Some(route);
+            ctx.guest = true;
+        });
         Ok(Auth::Accept)
     }
 
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        let client_version = String::from_utf8_lossy(session.remote_sshid()).into_owned();
+        self.context.update(|ctx| {
+            ctx.client_version = Some(client_version);
+            ctx.authenticated_at = Some(SystemTime::now());
+        });
+        log::info!("Session authenticated | {}", self.context.read());
+        Ok(())
+    }
+
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
+    ) -> Result<(), Self::Error> {
         let channel_id = channel.id();
         let handle = session.handle();
-        let prot = self.protocol.as_deref();
-
-        log::info!("Channel open session: {:?}", prot);
-        let app = if prot == Some("tui") {
-            let terminal_handle = TerminalHandle::start(handle.clone(), channel_id).await;
-            let mut app = App::start_tui(terminal_handle);
-            app.serve(None); // ?Initial Render
-            app
+        let tui = {
+            let ctx = self.context.read();
+            log::info!("Channel open session | {ctx}, channel: {channel_id}, route: {:?}", ctx.route);
+            ctx.route.as_deref() == Some("tui") || ctx.guest || ctx.totp_enrolment.is_some()
+        };
+
+        let (app, output) = if tui {
+            let (terminal_handle, output) = TerminalHandle::new();
+            let app = if self.context.read().totp_enrolment.is_some() {
+                App::start_totp_setup(terminal_handle, self.context.clone(), self.totp.clone())
+            } else {
+                App::start_tui(terminal_handle, self.context.clone())
+            };
+            (app, Some(output))
         } else {
-            App::start()
+            (App::start(self.context.clone()), None)
         };
 
-        self.clients.lock().await.insert(self.id, (channel_id, handle, app));
-        Ok(true)
+        let session = SessionActor::spawn(self.client_key(channel_id), app, output, handle, self.clients.clone()).await;
+        let channel = SessionChannel { state: ChannelState::Opened, session, details: ChannelDetails::default() };
+        self.channels.insert(channel_id, channel);
+        reply.accept().await;
+        Ok(())
     }
 
     async fn pty_request(
         &mut self,
         channel: ChannelId,
-        _term: &str,
+        term: &str,
         col_width: u32,
         row_height: u32,
         pix_width: u32,
         pix_height: u32,
-        _modes: &[(Pty, u32)],
+        modes: &[(Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         log::info!(
-            "PTY request: {}x{} ({}x{} pixels)", col_width, row_height, pix_width, pix_height
+            "PTY request | {}, term: {term}, {}x{} ({}x{} pixels)",
+            self.context.read(), col_width, row_height, pix_width, pix_height
         );
-        session.channel_success(channel)?;
-        let mut clients = self.clients.lock().await;
-
-        if let Some((_chan_id, _handle, app)) = clients.get_mut(&self.id) {
-            app.resize(col_width as u16, row_height as u16);
-            app.serve(None); // render pty size
+        if !self.context.read().pty_allowed() {
+            log::info!("PTY refused by key options | {}", self.context.read());
+            session.channel_failure(channel)?;
+            return Ok(());
         }
+        let Some(actor) = self.advance(channel, ChannelEvent::Pty) else {
+            session.channel_failure(channel)?;
+            return Ok(());
+        };
+        if let Some(c) = self.channels.get_mut(&channel) {
+            c.details.term = Some(term.to_string());
+            c.details.pty_modes = modes.to_vec();
+        }
+        actor.send(SessionMsg::Resize(col_width as u16, row_height as u16)).await; // render pty size
 
         session.channel_success(channel)?;
         Ok(())
     }
 
-    async fn shell_request(
+    /// Keeps the client's env vars on the channel, up to `MAX_ENV_VARS`.
+    async fn env_request(
         &mut self,
         channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let mut clients = self.clients.lock().await;
-        if let Some((_chan_id, _handle, app)) = clients.get_mut(&self.id) {
-            // Route based on protocol
-            let route = self.protocol.as_deref();
-            app.serve(route);
-
-            session.data(channel, CryptoVec::from(app.content.clone()))?;
+        if self.advance(channel, ChannelEvent::Env).is_none() {
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
+        let env = self.channels.get_mut(&channel).map(|c| &mut c.details.env);
+        let stored = env.is_some_and(|env| {
+            let room = env.len() < MAX_ENV_VARS || env.contains_key(variable_name);
+            if room {
+                env.insert(variable_name.to_string(), variable_value.to_string());
+            }
+            room
+        });
+        if stored {
             session.channel_success(channel)?;
         } else {
-            session.data(channel, CryptoVec::from("Session not found.\n"))?;
-            session.close(channel)?;
+            log::info!("Env var {variable_name} refused, over {MAX_ENV_VARS} | {}", self.context.read());
+            session.channel_failure(channel)?;
         }
+        Ok(())
+    }
 
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let details = self.channel_details(channel);
+        match self.advance(channel, ChannelEvent::Shell) {
+            Some(actor) if actor.send(SessionMsg::Shell(details)).await => session.channel_success(channel)?,
+            _ => session.channel_failure(channel)?,
+        }
+        Ok(())
+    }
+
+    /// Runs one of the commands in `commands`, then closes the channel.
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data).into_owned();
+        log::info!("Exec request | {}, command: {command:?}", self.context.read());
+        let pty = self.channels.get(&channel).is_some_and(|c| c.state == ChannelState::Pty);
+        let details = self.channel_details(channel);
+        match self.advance(channel, ChannelEvent::Exec) {
+            Some(actor) if actor.send(SessionMsg::Exec { command, pty, details }).await => session.channel_success(channel)?,
+            _ => session.channel_failure(channel)?,
+        }
         Ok(())
     }
 
     async fn window_change_request(
         &mut self,
-        _: ChannelId,
+        channel: ChannelId,
         col_width: u32,
         row_height: u32,
         _: u32,
         _: u32,
         _: &mut Session,
     ) -> Result<(), Self::Error> {
-        log::info!("Window resized: {}x{}", col_width, row_height);
+        log::info!("Window resized: {}x{} on channel {channel}", col_width, row_height);
 
-        let mut clients = self.clients.lock().await;
-
-        if let Some((_chan_id, _handle, app)) = clients.get_mut(&self.id) {
-            app.resize(col_width as u16, row_height as u16);
-            app.serve(None); // trigger re-render after resize
+        if let Some(actor) = self.advance(channel, ChannelEvent::WindowChange) {
+            actor.send(SessionMsg::Resize(col_width as u16, row_height as u16)).await;
         }
-
         Ok(())
     }
 
+    /// Hands input to the channel's actor, waiting while its mailbox is
+    /// full so the client's window fills up.
     async fn data(
         &mut self,
         channel: ChannelId,
         data: &[u8],
-        session: &mut Session,
+        _: &mut Session,
     ) -> Result<(), Self::Error> {
-        let mut clients = self.clients.lock().await;
-
-        if let Some((_chan_id, _handle, app)) = clients.get_mut(&self.id) {
-            let should_exit = app.handle_input(data);
-            app.serve(None);
-
-            if should_exit {
-                // Send clear screen escape sequence
-                let clear: &[u8] = b"\x1b[2J\x1b[H\r\n";
-                session.data(channel, CryptoVec::from(clear))?;
+        if let Some(actor) = self.advance(channel, ChannelEvent::Data) {
+            actor.send(SessionMsg::Input(data.to_vec())).await;
+        }
+        Ok(())
+    }
 
-                clients.remove(&self.id);
-                session.close(channel)?;
-                log::info!("Client close connection: {}", self.id);
-                log::info!("{:?}", self);
-            }
+    /// The client is done sending: the app gets to flush, then the channel
+    /// is closed.
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        _: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(actor) = self.advance(channel, ChannelEvent::Eof) {
+            actor.send(SessionMsg::Eof).await;
         }
         Ok(())
     }
-}
 
-impl Drop for SshServer {
-    fn drop(&mut self) {
-        let id = self.id;
-        let clients = self.clients.clone();
-        tokio::spawn(async move {
-            let mut clients = clients.lock().await;
-            clients.remove(&id);
-        });
+    /// Stops the channel's actor, which drops its app and registry entry;
+    /// the connection's other channels go on.
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(actor) = self.advance(channel, ChannelEvent::Close) {
+            actor.send(SessionMsg::Close).await;
+        }
+        self.channels.remove(&channel);
+        Ok(())
     }
 }
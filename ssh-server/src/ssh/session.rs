@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+use russh::Pty;
+use crate::ssh::authorized_keys::KeyOptions;
+
+/// Env vars kept per channel; further ones are refused.
+pub const MAX_ENV_VARS: usize = 64;
+
+/// Everything known about one connection, filled in as it authenticates
+/// and opens its channel.
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub id: usize,
+    pub peer: Option<SocketAddr>,
+    /// Username the client logged in with
+    pub username: Option<String>,
+    /// Route being served; `command=` and `force-command` can make it
+    /// differ from the username
+    pub route: Option<String>,
+    pub key_fingerprint: Option<String>,
+    pub key_algorithm: Option<String>,
+    /// Options of the authorized_keys entry or certificate used to log in
+    pub key_options: Option<KeyOptions>,
+    /// Logged in through keyboard-interactive without any key, so there is
+    /// no identity to tie member features to
+    pub guest: bool,
+    /// Set instead of `route` while a vetted key enrols for a `totp_routes`
+    /// route: the session only shows the new secret and takes a code
+    pub totp_enrolment: Option<TotpEnrolment>,
+    /// SSH identification string, e.g. `SSH-2.0-OpenSSH_9.6`
+    pub client_version: Option<String>,
+    pub connected_at: SystemTime,
+    pub authenticated_at: Option<SystemTime>,
+    /// Authentication attempts rejected on this connection
+    pub auth_failures: u32,
+}
+
+impl SessionContext {
+    pub fn new(id: usize, peer: Option<SocketAddr>) -> Self {
+        Self {
+            id,
+            peer,
+            username: None,
+            route: None,
+            key_fingerprint: None,
+            key_algorithm: None,
+            key_options: None,
+            guest: false,
+            totp_enrolment: None,
+            client_version: None,
+            connected_at: SystemTime::now(),
+            authenticated_at: None,
+            auth_failures: 0,
+        }
+    }
+
+    /// Whether the session may allocate a pty under its key options.
+    pub fn pty_allowed(&self) -> bool {
+        self.key_options.as_ref().is_none_or(KeyOptions::pty_allowed)
+    }
+
+    /// What the TUI shows users about their own connection and `channel`.
+    pub fn details(&self, channel: &ChannelDetails) -> Vec<(String, String)> {
+        let mut details = vec![
+            ("user".to_string(), self.username.clone().unwrap_or_default()),
+            ("route".to_string(), self.route.clone().unwrap_or_default()),
+        ];
+        let key = match (&self.key_algorithm, &self.key_fingerprint) {
+            (Some(algorithm), Some(fingerprint)) => format!("{algorithm} {fingerprint}"),
+            _ if self.guest => "none (guest)".to_string(),
+            _ => String::new(),
+        };
+        details.push(("key".to_string(), key));
+        if let Some(peer) = self.peer {
+            details.push(("from".to_string(), peer.to_string()));
+        }
+        if let Some(version) = &self.client_version {
+            details.push(("client".to_string(), version.clone()));
+        }
+        if let Some(term) = &channel.term {
+            details.push(("terminal".to_string(), term.clone()));
+        }
+        let online = self.authenticated_at.unwrap_or(self.connected_at).elapsed().unwrap_or_default().as_secs();
+        details.push(("online".to_string(), format!("{}m {:02}s", online / 60, online % 60)));
+        details
+    }
+}
+
+/// The secret a key is shown on its first login to a `totp_routes` route.
+#[derive(Debug, Clone)]
+pub struct TotpEnrolment {
+    pub route: String,
+    pub otpauth_uri: String,
+}
+
+/// What a session channel's pty and env requests set. Each channel of a
+/// multiplexed connection has its own.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelDetails {
+    /// `TERM` from the pty request
+    pub term: Option<String>,
+    pub pty_modes: Vec<(Pty, u32)>,
+    pub env: BTreeMap<String, String>,
+}
+
+/// `#id user@peer`, for log lines.
+impl fmt::Display for SessionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.id)?;
+        if let Some(username) = &self.username {
+            write!(f, " {username}")?;
+        }
+        match self.peer {
+            Some(peer) => write!(f, "@{peer}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A session's context shared between its handler, the clients map and the
+/// app rendering it.
+#[derive(Debug, Clone)]
+pub struct SharedContext(Arc<RwLock<SessionContext>>);
+
+impl SharedContext {
+    pub fn new(context: SessionContext) -> Self {
+        Self(Arc::new(RwLock::new(context)))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, SessionContext> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut SessionContext)) {
+        f(&mut self.0.write().unwrap_or_else(PoisonError::into_inner));
+    }
+}
@@ -1,6 +1,5 @@
 use std::io::{self, Write};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use russh::{ChannelId, server::Handle};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 #[derive(Debug)]
 pub struct TerminalHandle {
@@ -9,14 +8,11 @@ pub struct TerminalHandle {
 }
 
 impl TerminalHandle {
-    pub async fn start(handle: Handle, channel: ChannelId) -> Self {
-        let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
-        tokio::spawn(async move {
-            while let Some(data) = receiver.recv().await {
-                let _ = handle.data(channel, data.into()).await;
-            }
-        });
-        Self { sender, sink: Vec::new() }
+    /// Returns the terminal and the queue of frames it flushes. The session
+    /// actor forwards them, so they stay in order with its own writes.
+    pub fn new() -> (Self, UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = unbounded_channel::<Vec<u8>>();
+        (Self { sender, sink: Vec::new() }, receiver)
     }
 }
 
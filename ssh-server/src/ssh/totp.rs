@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::ssh::keypair::write_atomic;
+
+const ISSUER: &str = "culturecode";
+const STEP: u64 = 30;
+
+#[derive(Debug, Clone)]
+struct Enrolment {
+    /// Base32 secret
+    secret: String,
+    /// Time step of the last accepted code, so a code works once
+    last_step: u64,
+}
+
+/// TOTP secrets per client key fingerprint, kept in a `fingerprint secret
+/// last_step` file readable by the server only. A new secret stays in
+/// memory until a code from it is entered.
+#[derive(Debug)]
+pub struct TotpStore {
+    entries: Mutex<HashMap<String, Enrolment>>,
+    /// Secrets handed out but not confirmed by a code yet
+    pending: Mutex<HashMap<String, String>>,
+    path: Option<PathBuf>,
+}
+
+impl TotpStore {
+    pub fn open(path: Option<&Path>) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        if let Some(path) = path.filter(|p| p.exists()) {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let [fingerprint, secret, last_step] = fields[..] else {
+                    return Err(format!("{}:{}: expected `fingerprint secret last_step`", path.display(), i + 1));
+                };
+                let last_step = last_step.parse()
+                    .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+                entries.insert(fingerprint.to_string(), Enrolment { secret: secret.to_string(), last_step });
+            }
+            log::info!("Loaded {} TOTP enrolment(s) from {}", entries.len(), path.display());
+        }
+        Ok(Self { entries: Mutex::new(entries), pending: Mutex::new(HashMap::new()), path: path.map(Path::to_path_buf) })
+    }
+
+    pub async fn is_enrolled(&self, fingerprint: &str) -> bool {
+        self.entries.lock().await.contains_key(fingerprint)
+    }
+
+    /// The otpauth URI of a new secret for `fingerprint`. Nothing is saved:
+    /// the secret only counts once `verify` accepts a code from it, and the
+    /// same one is handed out until then.
+    pub async fn start_enrolment(&self, fingerprint: &str, account: &str) -> Result<String, String> {
+        if self.path.is_none() {
+            return Err("totp_secrets_path is not set (changing it needs a restart)".to_string());
+        }
+        if self.is_enrolled(fingerprint).await {
+            return Err("already enrolled".to_string());
+        }
+        let mut pending = self.pending.lock().await;
+        let secret = pending.entry(fingerprint.to_string())
+            .or_insert_with(|| Secret::generate_secret().to_encoded().to_string());
+        Ok(totp(secret, account)?.get_url())
+    }
+
+    /// Checks `code` for the current time step, allowing one step of clock
+    /// skew either way. Each step's code is accepted once. A code from a
+    /// pending secret completes the enrolment. Nothing changes in memory
+    /// unless the file was saved, so a failed write does not use up the code.
+    pub async fn verify(&self, fingerprint: &str, code: &str) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+        self.verify_at(fingerprint, code, now).await
+    }
+
+    async fn verify_at(&self, fingerprint: &str, code: &str, now: u64) -> Result<(), String> {
+        let mut entries = self.entries.lock().await;
+        let mut pending = self.pending.lock().await;
+        let (secret, last_step) = match entries.get(fingerprint) {
+            Some(enrolment) => (enrolment.secret.clone(), enrolment.last_step),
+            None => (pending.get(fingerprint).ok_or("not enrolled")?.clone(), 0),
+        };
+        let last_step = check(&secret, code, last_step, now)?;
+        let mut updated = entries.clone();
+        updated.insert(fingerprint.to_string(), Enrolment { secret, last_step });
+        self.save(&updated)?;
+        *entries = updated;
+        pending.remove(fingerprint);
+        Ok(())
+    }
+
+    fn save(&self, entries: &HashMap<String, Enrolment>) -> Result<(), String> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut text = String::new();
+        for (fingerprint, enrolment) in entries {
+            text.push_str(&format!("{} {} {}\n", fingerprint, enrolment.secret, enrolment.last_step));
+        }
+        write_atomic(path, text.as_bytes(), 0o600)
+    }
+}
+
+/// The time step `code` belongs to at `now`, if it is valid and newer than
+/// `last_step`.
+fn check(secret: &str, code: &str, last_step: u64, now: u64) -> Result<u64, String> {
+    let totp = totp(secret, "")?;
+    let current = now / STEP;
+    let step = (current.saturating_sub(1)..=current + 1)
+        .find(|step| totp.check(code, step * STEP))
+        .ok_or("wrong code")?;
+    if step <= last_step {
+        return Err("code already used".to_string());
+    }
+    Ok(step)
+}
+
+/// SHA-1, six digits and 30 second steps: what authenticator apps assume.
+fn totp(secret: &str, account: &str) -> Result<TOTP, String> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| format!("Invalid TOTP secret: {:?}", e))?;
+    TOTP::new(Algorithm::SHA1, 6, 0, STEP, bytes, Some(ISSUER.to_string()), account.replace(':', "-"))
+        .map_err(|e| format!("Invalid TOTP parameters: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "SHA256:test";
+    /// Mid-step, so a step either way is a whole step away
+    const NOW: u64 = 1_700_000_010;
+
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ssh-server-totp-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn code(secret: &str, time: u64) -> String {
+        totp(secret, "").unwrap().generate(time)
+    }
+
+    fn enrolled(path: &Path, last_step: u64) -> (TotpStore, String) {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        fs::write(path, format!("{FINGERPRINT} {secret} {last_step}\n")).unwrap();
+        (TotpStore::open(Some(path)).unwrap(), secret)
+    }
+
+    async fn last_step(store: &TotpStore) -> u64 {
+        store.entries.lock().await[FINGERPRINT].last_step
+    }
+
+    #[tokio::test]
+    async fn one_step_of_skew_either_way() {
+        let path = path("skew");
+        let (store, secret) = enrolled(&path, 0);
+        let current = NOW / STEP;
+
+        assert!(store.verify_at(FINGERPRINT, &code(&secret, NOW - 2 * STEP), NOW).await.is_err());
+        store.verify_at(FINGERPRINT, &code(&secret, NOW - STEP), NOW).await.unwrap();
+        assert_eq!(last_step(&store).await, current - 1);
+        store.verify_at(FINGERPRINT, &code(&secret, NOW + STEP), NOW).await.unwrap();
+        assert_eq!(last_step(&store).await, current + 1);
+        assert!(store.verify_at(FINGERPRINT, &code(&secret, NOW + 2 * STEP), NOW).await.is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn codes_work_once() {
+        let path = path("replay");
+        let (store, secret) = enrolled(&path, 0);
+        let current = code(&secret, NOW);
+
+        store.verify_at(FINGERPRINT, &current, NOW).await.unwrap();
+        assert_eq!(store.verify_at(FINGERPRINT, &current, NOW).await, Err("code already used".to_string()));
+        let earlier = code(&secret, NOW - STEP);
+        assert_eq!(store.verify_at(FINGERPRINT, &earlier, NOW).await, Err("code already used".to_string()));
+
+        let reopened = TotpStore::open(Some(&path)).unwrap();
+        assert_eq!(reopened.verify_at(FINGERPRINT, &current, NOW).await, Err("code already used".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn first_code_completes_the_enrolment() {
+        let path = path("enrol");
+        let store = TotpStore::open(Some(&path)).unwrap();
+        let uri = store.start_enrolment(FINGERPRINT, "admin").await.unwrap();
+        assert_eq!(store.start_enrolment(FINGERPRINT, "admin").await.unwrap(), uri);
+        let secret = store.pending.lock().await[FINGERPRINT].clone();
+
+        assert!(store.verify_at(FINGERPRINT, &code(&secret, NOW + 5 * STEP), NOW).await.is_err());
+        assert!(!store.is_enrolled(FINGERPRINT).await);
+        assert!(!path.exists());
+
+        store.verify_at(FINGERPRINT, &code(&secret, NOW), NOW).await.unwrap();
+        assert!(store.is_enrolled(FINGERPRINT).await);
+        assert!(store.pending.lock().await.is_empty());
+        assert!(store.start_enrolment(FINGERPRINT, "admin").await.is_err());
+        assert!(TotpStore::open(Some(&path)).unwrap().is_enrolled(FINGERPRINT).await);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_save_keeps_the_code() {
+        let path = std::env::temp_dir().join("ssh-server-totp-missing-dir").join("secrets");
+        let store = TotpStore::open(Some(&path)).unwrap();
+        store.start_enrolment(FINGERPRINT, "admin").await.unwrap();
+        let secret = store.pending.lock().await[FINGERPRINT].clone();
+
+        assert!(store.verify_at(FINGERPRINT, &code(&secret, NOW), NOW).await.is_err());
+        assert!(!store.is_enrolled(FINGERPRINT).await);
+        assert_eq!(store.pending.lock().await[FINGERPRINT], secret);
+
+        store.entries.lock().await.insert(FINGERPRINT.to_string(), Enrolment { secret: secret.clone(), last_step: 0 });
+        assert!(store.verify_at(FINGERPRINT, &code(&secret, NOW), NOW).await.is_err());
+        assert_eq!(last_step(&store).await, 0);
+    }
+}
@@ -0,0 +1,50 @@
+#!/bin/sh
+# Regenerates the user CA and KRL fixtures. Private keys are not kept.
+set -e
+cd "$(dirname "$0")"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+key() { ssh-keygen -q -t ed25519 -N '' -C "$1" -f "$tmp/$1"; }
+sign() { ca=$1 name=$2 serial=$3; shift 3; ssh-keygen -q -s "$tmp/$ca" -I "$name" -z "$serial" "$@" "$tmp/$name.pub"; cp "$tmp/$name-cert.pub" .; }
+
+for k in ca other-ca revokedca alice nopty forced critical noprincipal host untrusted expired \
+    serial7 bitmap range list mallory key hash sha1 sha256 byrevokedca; do key $k; done
+cat "$tmp/ca.pub" "$tmp/revokedca.pub" > ca_keys
+
+forever="-V always:forever"
+sign ca alice 1 -n alice,admin $forever
+sign ca nopty 2 -n alice -O clear $forever
+sign ca forced 3 -n alice -O force-command=admin -O source-address=10.0.0.0/8,192.0.2.1 $forever
+sign ca critical 4 -n alice -O verify-required $forever
+sign ca noprincipal 5 $forever
+sign ca host 6 -h -n alice $forever
+sign other-ca untrusted 1 -n alice $forever
+sign ca expired 8 -n alice -V 20200101:20200102
+sign ca serial7 7 -n alice $forever
+sign ca bitmap 214 -n alice $forever
+sign ca range 50000 -n alice $forever
+sign ca list 9000000 -n alice $forever
+sign ca mallory 9 -n alice $forever
+for k in key hash sha1 sha256; do sign ca $k 10 -n alice $forever; done
+sign revokedca byrevokedca 1 -n alice $forever
+
+{
+    echo "serial: 7"
+    echo "serial: 20-40"
+    echo "serial: 100"
+    echo "serial: 103"
+    echo "serial: 131"
+    echo "id: mallory"
+    echo "key: $(cat "$tmp/key.pub")"
+    echo "hash: $(ssh-keygen -lf "$tmp/hash.pub" | cut -d' ' -f2)"
+    echo "sha1: $(cat "$tmp/sha1.pub")"
+    echo "sha256: $(cat "$tmp/sha256.pub")"
+    echo "key: $(cat "$tmp/revokedca.pub")"
+    for s in 200 202 204 206 208 210 212 214 216 218 220 222; do echo "serial: $s"; done
+    echo "serial: 1000-100000"
+    echo "serial: 5000000"
+    echo "serial: 9000000"
+} > revoked.txt
+rm -f revoked.krl
+ssh-keygen -q -k -f revoked.krl -s "$tmp/ca.pub" revoked.txt
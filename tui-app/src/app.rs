@@ -8,13 +8,24 @@ use ratatui::{
 };
 use crate::brand;
 
+use crate::components::guest::key_guide_paragraph;
+use crate::components::session::session_paragraph;
+use crate::components::totp::{totp_setup_paragraph, TotpSetup, CODE_LENGTH};
 use crate::components::welcome::*;
 
 #[derive(Debug, Default)]
 pub struct App {
     pub input_buffer: String,
     running: bool,
+    /// Asked to leave with a key other than the `200` sequence
+    exit: bool,
     show_link: bool,
+    /// Logged in without a key: shows the key guide and hides member-only content
+    pub guest: bool,
+    /// Enrolling the key for two-factor: shown instead of everything else
+    pub totp_setup: Option<TotpSetup>,
+    /// Label/value pairs about the client's own connection, set by the host
+    pub details: Vec<(String, String)>,
 }
 
 impl App {
@@ -22,9 +33,21 @@ impl App {
         Self::default()
     }
 
+    pub fn guest() -> Self {
+        Self { guest: true, ..Self::default() }
+    }
+
+    pub fn totp_setup(route: String, otpauth_uri: String) -> Self {
+        Self { totp_setup: Some(TotpSetup::new(route, otpauth_uri)), ..Self::default() }
+    }
+
+    pub fn exit_requested(&self) -> bool {
+        self.exit || self.input_buffer == "200"
+    }
+
     pub fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
         self.running = true;
-        while self.running {
+        while self.running && !self.exit {
             terminal.draw(|frame| self.render(frame))?;
             self.handle_crossterm_events()?;
         }
@@ -51,10 +74,16 @@ impl App {
             .alignment(Alignment::Center);
         frame.render_widget(logo, layout[0]);
 
+        let details_height = if self.details.is_empty() || self.totp_setup.is_some() {
+            0
+        } else {
+            self.details.len() as u16 + 2
+        };
         let content_layout = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
-                Constraint::Length(12),  // First paragraph block height
+                Constraint::Length(if self.totp_setup.is_some() { 14 } else { 12 }),  // First paragraph block height
+                Constraint::Length(details_height),  // Session details, when known
                 Constraint::Length(25),  // Second paragraph block height
             ])
             .split(layout[1]);
@@ -63,12 +92,22 @@ impl App {
         let inner1 = block1.inner(content_layout[0]);
         frame.render_widget(block1, content_layout[0]);
 
-        let paragraph1 = welcome_paragraph(self.show_link);
+        let paragraph1 = if let Some(setup) = &self.totp_setup {
+            totp_setup_paragraph(setup)
+        } else if self.guest {
+            key_guide_paragraph()
+        } else {
+            welcome_paragraph(self.show_link)
+        };
         frame.render_widget(paragraph1, inner1);
 
+        if details_height > 0 {
+            frame.render_widget(session_paragraph(&self.details), content_layout[1]);
+        }
+
         let block2 = Block::default();
-        let inner2 = block2.inner(content_layout[1]);
-        frame.render_widget(block2, content_layout[1]);
+        let inner2 = block2.inner(content_layout[2]);
+        frame.render_widget(block2, content_layout[2]);
 
         let paragraph2 = welcome_paragraph_end();
         frame.render_widget(paragraph2, inner2);
@@ -86,6 +125,19 @@ impl App {
     }
 
     pub fn on_key_event(&mut self, key: KeyEvent) {
+        if let Some(setup) = &mut self.totp_setup {
+            match key.code {
+                KeyCode::Esc => self.exit = true,
+                _ if setup.is_done() => {}
+                KeyCode::Char(c) if c.is_ascii_digit() && setup.code.len() < CODE_LENGTH => setup.code.push(c),
+                KeyCode::Backspace => {
+                    setup.code.pop();
+                }
+                KeyCode::Enter if setup.code.len() == CODE_LENGTH => setup.submitted = Some(std::mem::take(&mut setup.code)),
+                _ => {}
+            }
+            return;
+        }
         if let KeyCode::Char(c) = key.code {
             self.input_buffer.push(c);
 
@@ -97,10 +149,8 @@ impl App {
                 self.running = false;
             }
 
-            if c == 'd' || c == 'D' {
-                if !self.show_link {
-                    self.show_link = true;
-                }
+            if (c == 'd' || c == 'D') && !self.show_link && !self.guest {
+                self.show_link = true;
             }
         }
     }
@@ -0,0 +1,74 @@
+use ratatui::{
+    text::{Line, Span, Text},
+    widgets::Paragraph,
+    style::{Style, Modifier},
+    layout::Alignment,
+};
+use crate::brand::*;
+
+pub fn key_guide_paragraph() -> Paragraph<'static> {
+    let lines = vec![
+        Line::from(
+            Span::styled("────────────────────────────────────────────────────",
+                Style::default()
+                    .fg(BrandColors::DarkGray.color())
+                    .add_modifier(Modifier::BOLD))
+        ),
+        Line::from(vec![
+            Span::styled("Welcome, ",
+                Style::default()
+                    .fg(BrandColors::Gray.color())
+            ),
+            Span::styled("guest",
+                Style::default()
+                    .fg(BrandColors::Peach.color())
+                    .add_modifier(Modifier::BOLD)),
+            Span::styled(" — you need a ",
+                Style::default()
+                    .fg(BrandColors::Gray.color())
+            ),
+            Span::styled("PublicKey",
+                Style::default()
+                    .fg(BrandColors::Lavender.color())
+                    .add_modifier(Modifier::BOLD)),
+            Span::styled(" to join",
+                Style::default()
+                    .fg(BrandColors::Gray.color())
+            ),
+        ]),
+        Line::from(""),
+        Line::from(
+            Span::styled("1. Generate one on your machine:",
+                Style::default()
+                    .fg(BrandColors::Gray.color()))
+        ),
+        Line::from(
+            Span::styled("ssh-keygen -t ed25519 -C \"you@example.com\"",
+                Style::default()
+                    .fg(BrandColors::Mint.color())
+                    .add_modifier(Modifier::BOLD))
+        ),
+        Line::from(
+            Span::styled("2. Keep the default file and choose a passphrase",
+                Style::default()
+                    .fg(BrandColors::Gray.color()))
+        ),
+        Line::from(
+            Span::styled("3. Reconnect: ssh offers ~/.ssh/id_ed25519 by itself",
+                Style::default()
+                    .fg(BrandColors::Gray.color()))
+        ),
+        Line::from(""),
+        Line::from(vec![
+            Span::styled("to exit type: ",
+                Style::default()
+                    .fg(BrandColors::DarkGray.color())),
+            Span::styled("'200'",
+                Style::default()
+                    .fg(BrandColors::Light.color())
+                    .add_modifier(Modifier::BOLD))
+        ]),
+    ];
+
+    Paragraph::new(Text::from(lines)).alignment(Alignment::Center)
+}
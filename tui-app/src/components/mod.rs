@@ -1,2 +1,5 @@
 pub mod discord;
+pub mod guest;
+pub mod session;
+pub mod totp;
 pub mod welcome;
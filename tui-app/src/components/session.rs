@@ -0,0 +1,31 @@
+use ratatui::{
+    text::{Line, Span, Text},
+    widgets::Paragraph,
+    style::{Style, Modifier},
+    layout::Alignment,
+};
+use crate::brand::*;
+
+/// The client's own connection details, as `label value` lines.
+pub fn session_paragraph(details: &[(String, String)]) -> Paragraph<'static> {
+    let mut lines = vec![
+        Line::from(
+            Span::styled("your session",
+                Style::default()
+                    .fg(BrandColors::DarkGray.color())
+                    .add_modifier(Modifier::BOLD))
+        ),
+    ];
+    for (label, value) in details {
+        lines.push(Line::from(vec![
+            Span::styled(format!("{label}: "),
+                Style::default()
+                    .fg(BrandColors::Gray.color())),
+            Span::styled(value.clone(),
+                Style::default()
+                    .fg(BrandColors::Lavender.color())),
+        ]));
+    }
+
+    Paragraph::new(Text::from(lines)).alignment(Alignment::Center)
+}
@@ -0,0 +1,111 @@
+use ratatui::{
+    text::{Line, Span, Text},
+    widgets::{Paragraph, Wrap},
+    style::{Style, Modifier},
+    layout::Alignment,
+};
+use crate::brand::*;
+
+/// Digits in an authenticator code
+pub const CODE_LENGTH: usize = 6;
+
+/// A key's two-factor setup: the secret to add to an authenticator app and
+/// the first code from it, which the host checks.
+#[derive(Debug, Default)]
+pub struct TotpSetup {
+    /// Route the key gets into once enrolled
+    pub route: String,
+    pub otpauth_uri: String,
+    /// Digits typed so far
+    pub code: String,
+    /// A complete code, set on Enter and taken by the host
+    pub submitted: Option<String>,
+    /// Outcome of the last check; `Ok` once enrolled
+    pub result: Option<Result<(), String>>,
+}
+
+impl TotpSetup {
+    pub fn new(route: String, otpauth_uri: String) -> Self {
+        Self { route, otpauth_uri, ..Self::default() }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.result, Some(Ok(())))
+    }
+}
+
+pub fn totp_setup_paragraph(setup: &TotpSetup) -> Paragraph<'static> {
+    let mut lines = vec![
+        Line::from(
+            Span::styled("────────────────────────────────────────────────────",
+                Style::default()
+                    .fg(BrandColors::DarkGray.color())
+                    .add_modifier(Modifier::BOLD))
+        ),
+        Line::from(vec![
+            Span::styled("Two-factor ",
+                Style::default()
+                    .fg(BrandColors::Light.color())
+                    .add_modifier(Modifier::BOLD)),
+            Span::styled("setup",
+                Style::default()
+                    .fg(BrandColors::Peach.color())
+                    .add_modifier(Modifier::BOLD)),
+        ]),
+        Line::from(""),
+    ];
+
+    if setup.is_done() {
+        lines.push(Line::from(
+            Span::styled(format!("All set. Log in again to reach the {} route.", setup.route),
+                Style::default()
+                    .fg(BrandColors::Mint.color())
+                    .add_modifier(Modifier::BOLD))
+        ));
+    } else {
+        lines.push(Line::from(
+            Span::styled("Add this to your authenticator app:",
+                Style::default()
+                    .fg(BrandColors::Gray.color()))
+        ));
+        lines.push(Line::from(
+            Span::styled(setup.otpauth_uri.clone(),
+                Style::default()
+                    .fg(BrandColors::Mint.color())
+                    .add_modifier(Modifier::BOLD))
+        ));
+        lines.push(Line::from(""));
+        let typed = format!("{:_<width$}", setup.code, width = CODE_LENGTH);
+        lines.push(Line::from(vec![
+            Span::styled("then type its code and press Enter: ",
+                Style::default()
+                    .fg(BrandColors::Gray.color())),
+            Span::styled(typed,
+                Style::default()
+                    .fg(BrandColors::Light.color())
+                    .add_modifier(Modifier::BOLD)),
+        ]));
+        if let Some(Err(reason)) = &setup.result {
+            lines.push(Line::from(
+                Span::styled(reason.clone(),
+                    Style::default()
+                        .fg(BrandColors::Coral.color()))
+            ));
+        }
+    }
+
+    lines.push(Line::from(""));
+    lines.push(Line::from(vec![
+        Span::styled("to exit press: ",
+            Style::default()
+                .fg(BrandColors::DarkGray.color())),
+        Span::styled("Esc",
+            Style::default()
+                .fg(BrandColors::Light.color())
+                .add_modifier(Modifier::BOLD))
+    ]));
+
+    Paragraph::new(Text::from(lines))
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: false })
+}
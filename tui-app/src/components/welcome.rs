@@ -6,6 +6,9 @@ use ratatui::{
 };
 use crate::brand::*;
 
+/// Invite link revealed to members who press 'D'
+pub const INVITE_LINK: &str = "https://discord.gg/h9jMHgP9";
+
 pub fn welcome_paragraph(show_link: bool) -> Paragraph<'static> {
     let mut lines = vec![
         Line::from(
@@ -51,7 +54,7 @@ pub fn welcome_paragraph(show_link: bool) -> Paragraph<'static> {
             Span::styled("|          ",
                 Style::default()
                     .fg(BrandColors::DarkGray.color())),
-            Span::styled("https://discord.gg/h9jMHgP9",
+            Span::styled(INVITE_LINK,
                 Style::default()
                     .fg(BrandColors::Mint.color())
                     .add_modifier(Modifier::UNDERLINED | Modifier::BOLD)),
@@ -85,11 +88,9 @@ pub fn welcome_paragraph(show_link: bool) -> Paragraph<'static> {
     }
 
     lines.push(Line::from(
-        Line::from(
-            Span::styled("╰─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─╯",
-                Style::default()
-                    .fg(BrandColors::DarkGray.color()))
-        ),
+        Span::styled("╰─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─╯",
+            Style::default()
+                .fg(BrandColors::DarkGray.color()))
     ));
 
     if show_link {